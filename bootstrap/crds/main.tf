@@ -3,9 +3,28 @@ resource "kubernetes_manifest" "customresourcedefinition_trpports_demeter_run" {
     "apiVersion" = "apiextensions.k8s.io/v1"
     "kind"       = "CustomResourceDefinition"
     "metadata" = {
+      "annotations" = {
+        "cert-manager.io/inject-ca-from" = "${var.namespace}/operator-webhook"
+      }
       "name" = "trpports.demeter.run"
     }
     "spec" = {
+      "conversion" = {
+        "strategy" = "Webhook"
+        "webhook" = {
+          "clientConfig" = {
+            "service" = {
+              "name"      = "operator-webhook"
+              "namespace" = "${var.namespace}"
+              "path"      = "/convert"
+              "port"      = 443
+            }
+          }
+          "conversionReviewVersions" = [
+            "v1",
+          ]
+        }
+      }
       "group" = "demeter.run"
       "names" = {
         "categories" = [
@@ -38,8 +57,13 @@ resource "kubernetes_manifest" "customresourcedefinition_trpports_demeter_run" {
               "type"     = "string"
             },
             {
-              "jsonPath" = ".status.authToken"
-              "name"     = "Auth Token"
+              "jsonPath" = ".status.authTokenSecret"
+              "name"     = "Auth Secret"
+              "type"     = "string"
+            },
+            {
+              "jsonPath" = ".status.conditions[?(@.type==\"Ready\")].status"
+              "name"     = "Ready"
               "type"     = "string"
             },
           ]
@@ -49,9 +73,32 @@ resource "kubernetes_manifest" "customresourcedefinition_trpports_demeter_run" {
               "description" = "Auto-generated derived type for TrpPortSpec via `CustomResource`"
               "properties" = {
                 "spec" = {
+                  "description" = "First version of the port, still served for existing clients. Objects are stored as the latest version and converted by the conversion webhook."
                   "properties" = {
+                    "authKeys" = {
+                      "items" = {
+                        "properties" = {
+                          "notAfter" = {
+                            "format"   = "date-time"
+                            "nullable" = true
+                            "type"     = "string"
+                          }
+                          "secretName" = {
+                            "description" = "Secret in the namespace of the port holding the key under `token`."
+                            "type"        = "string"
+                          }
+                        }
+                        "required" = [
+                          "secretName",
+                        ]
+                        "type" = "object"
+                      }
+                      "type" = "array"
+                    }
                     "authToken" = {
-                      "type" = "string"
+                      "default"     = ""
+                      "description" = "Left empty to let the operator generate one."
+                      "type"        = "string"
                     }
                     "network" = {
                       "type" = "string"
@@ -61,7 +108,6 @@ resource "kubernetes_manifest" "customresourcedefinition_trpports_demeter_run" {
                     }
                   }
                   "required" = [
-                    "authToken",
                     "network",
                     "throughputTier",
                   ]
@@ -70,15 +116,363 @@ resource "kubernetes_manifest" "customresourcedefinition_trpports_demeter_run" {
                 "status" = {
                   "nullable" = true
                   "properties" = {
+                    "authKeys" = {
+                      "default" = []
+                      "items" = {
+                        "properties" = {
+                          "hint" = {
+                            "description" = "Start of the SHA-256 hash of the key, as indexed by the proxy."
+                            "type"        = "string"
+                          }
+                          "notAfter" = {
+                            "format"   = "date-time"
+                            "nullable" = true
+                            "type"     = "string"
+                          }
+                          "state" = {
+                            "enum" = [
+                              "Active",
+                              "Expiring",
+                            ]
+                            "type" = "string"
+                          }
+                        }
+                        "required" = [
+                          "hint",
+                          "state",
+                        ]
+                        "type" = "object"
+                      }
+                      "type" = "array"
+                    }
+                    "authTokenSecret" = {
+                      "default"     = ""
+                      "description" = "Name of the Secret holding the auth token."
+                      "type"        = "string"
+                    }
+                    "conditions" = {
+                      "default" = []
+                      "items" = {
+                        "description" = "Follows the fields of the standard Kubernetes condition, so `kubectl wait --for=condition=` works on ports."
+                        "properties" = {
+                          "lastTransitionTime" = {
+                            "format" = "date-time"
+                            "type"   = "string"
+                          }
+                          "message" = {
+                            "type" = "string"
+                          }
+                          "observedGeneration" = {
+                            "format"   = "int64"
+                            "nullable" = true
+                            "type"     = "integer"
+                          }
+                          "reason" = {
+                            "type" = "string"
+                          }
+                          "status" = {
+                            "enum" = [
+                              "True",
+                              "False",
+                              "Unknown",
+                            ]
+                            "type" = "string"
+                          }
+                          "type" = {
+                            "type" = "string"
+                          }
+                        }
+                        "required" = [
+                          "lastTransitionTime",
+                          "message",
+                          "reason",
+                          "status",
+                          "type",
+                        ]
+                        "type" = "object"
+                      }
+                      "type" = "array"
+                    }
+                    "endpointUrl" = {
+                      "type" = "string"
+                    }
+                    "observedGeneration" = {
+                      "format"   = "int64"
+                      "nullable" = true
+                      "type"     = "integer"
+                    }
+                    "usage" = {
+                      "description" = "Written by the usage collector, the reconciler leaves it untouched."
+                      "nullable"    = true
+                      "properties" = {
+                        "billingPeriodStart" = {
+                          "format" = "date-time"
+                          "type"   = "string"
+                        }
+                        "dayStart" = {
+                          "format" = "date-time"
+                          "type"   = "string"
+                        }
+                        "lastUpdated" = {
+                          "format" = "date-time"
+                          "type"   = "string"
+                        }
+                        "unitsBillingPeriod" = {
+                          "format"  = "uint64"
+                          "minimum" = 0
+                          "type"    = "integer"
+                        }
+                        "unitsToday" = {
+                          "format"  = "uint64"
+                          "minimum" = 0
+                          "type"    = "integer"
+                        }
+                      }
+                      "required" = [
+                        "billingPeriodStart",
+                        "dayStart",
+                        "lastUpdated",
+                        "unitsBillingPeriod",
+                        "unitsToday",
+                      ]
+                      "type" = "object"
+                    }
+                  }
+                  "required" = [
+                    "endpointUrl",
+                  ]
+                  "type" = "object"
+                }
+              }
+              "required" = [
+                "spec",
+              ]
+              "title" = "TrpPort"
+              "type"  = "object"
+            }
+          }
+          "served"  = true
+          "storage" = false
+          "subresources" = {
+            "status" = {}
+          }
+        },
+        {
+          "additionalPrinterColumns" = [
+            {
+              "jsonPath" = ".spec.network"
+              "name"     = "Network"
+              "type"     = "string"
+            },
+            {
+              "jsonPath" = ".spec.throughputTier"
+              "name"     = "Throughput Tier"
+              "type"     = "string"
+            },
+            {
+              "jsonPath" = ".status.endpointUrl"
+              "name"     = "Endpoint URL"
+              "type"     = "string"
+            },
+            {
+              "jsonPath" = ".status.authTokenSecret"
+              "name"     = "Auth Secret"
+              "type"     = "string"
+            },
+            {
+              "jsonPath" = ".status.conditions[?(@.type==\"Ready\")].status"
+              "name"     = "Ready"
+              "type"     = "string"
+            },
+          ]
+          "name" = "v1beta1"
+          "schema" = {
+            "openAPIV3Schema" = {
+              "description" = "Auto-generated derived type for TrpPortSpec via `CustomResource`"
+              "properties" = {
+                "spec" = {
+                  "properties" = {
+                    "authKeys" = {
+                      "description" = "Extra keys accepted for the port, used to rotate the auth token without downtime. Their tokens are read from Secrets, never from the spec."
+                      "items" = {
+                        "properties" = {
+                          "notAfter" = {
+                            "format"   = "date-time"
+                            "nullable" = true
+                            "type"     = "string"
+                          }
+                          "secretName" = {
+                            "description" = "Secret in the namespace of the port holding the key under `token`."
+                            "type"        = "string"
+                          }
+                        }
+                        "required" = [
+                          "secretName",
+                        ]
+                        "type" = "object"
+                      }
+                      "type" = "array"
+                    }
                     "authToken" = {
+                      "description" = "Left unset to let the operator generate one. The effective token is always stored in the Secret referenced by the status."
+                      "nullable"    = true
+                      "type"        = "string"
+                    }
+                    "monthlyQuota" = {
+                      "description" = "Units allowed per billing period, overriding the quota of the tier. Requests are weighted by the method costs of the tier, as the proxy rate limiter does."
+                      "format"      = "uint64"
+                      "minimum"     = 0
+                      "nullable"    = true
+                      "type"        = "integer"
+                    }
+                    "network" = {
                       "type" = "string"
                     }
+                    "suspendReason" = {
+                      "description" = "Shown to the clients of a suspended port."
+                      "nullable"    = true
+                      "type"        = "string"
+                    }
+                    "suspended" = {
+                      "description" = "Keeps the port and its keys, but the proxy denies its requests."
+                      "type"        = "boolean"
+                    }
+                    "throughputTier" = {
+                      "type" = "string"
+                    }
+                  }
+                  "required" = [
+                    "network",
+                    "throughputTier",
+                  ]
+                  "type" = "object"
+                }
+                "status" = {
+                  "nullable" = true
+                  "properties" = {
+                    "authKeys" = {
+                      "default" = []
+                      "items" = {
+                        "properties" = {
+                          "hint" = {
+                            "description" = "Start of the SHA-256 hash of the key, as indexed by the proxy."
+                            "type"        = "string"
+                          }
+                          "notAfter" = {
+                            "format"   = "date-time"
+                            "nullable" = true
+                            "type"     = "string"
+                          }
+                          "state" = {
+                            "enum" = [
+                              "Active",
+                              "Expiring",
+                            ]
+                            "type" = "string"
+                          }
+                        }
+                        "required" = [
+                          "hint",
+                          "state",
+                        ]
+                        "type" = "object"
+                      }
+                      "type" = "array"
+                    }
+                    "authTokenSecret" = {
+                      "default"     = ""
+                      "description" = "Name of the Secret holding the auth token."
+                      "type"        = "string"
+                    }
+                    "conditions" = {
+                      "default" = []
+                      "items" = {
+                        "description" = "Follows the fields of the standard Kubernetes condition, so `kubectl wait --for=condition=` works on ports."
+                        "properties" = {
+                          "lastTransitionTime" = {
+                            "format" = "date-time"
+                            "type"   = "string"
+                          }
+                          "message" = {
+                            "type" = "string"
+                          }
+                          "observedGeneration" = {
+                            "format"   = "int64"
+                            "nullable" = true
+                            "type"     = "integer"
+                          }
+                          "reason" = {
+                            "type" = "string"
+                          }
+                          "status" = {
+                            "enum" = [
+                              "True",
+                              "False",
+                              "Unknown",
+                            ]
+                            "type" = "string"
+                          }
+                          "type" = {
+                            "type" = "string"
+                          }
+                        }
+                        "required" = [
+                          "lastTransitionTime",
+                          "message",
+                          "reason",
+                          "status",
+                          "type",
+                        ]
+                        "type" = "object"
+                      }
+                      "type" = "array"
+                    }
                     "endpointUrl" = {
                       "type" = "string"
                     }
+                    "observedGeneration" = {
+                      "format"   = "int64"
+                      "nullable" = true
+                      "type"     = "integer"
+                    }
+                    "usage" = {
+                      "description" = "Written by the usage collector, the reconciler leaves it untouched."
+                      "nullable"    = true
+                      "properties" = {
+                        "billingPeriodStart" = {
+                          "format" = "date-time"
+                          "type"   = "string"
+                        }
+                        "dayStart" = {
+                          "format" = "date-time"
+                          "type"   = "string"
+                        }
+                        "lastUpdated" = {
+                          "format" = "date-time"
+                          "type"   = "string"
+                        }
+                        "unitsBillingPeriod" = {
+                          "format"  = "uint64"
+                          "minimum" = 0
+                          "type"    = "integer"
+                        }
+                        "unitsToday" = {
+                          "format"  = "uint64"
+                          "minimum" = 0
+                          "type"    = "integer"
+                        }
+                      }
+                      "required" = [
+                        "billingPeriodStart",
+                        "dayStart",
+                        "lastUpdated",
+                        "unitsBillingPeriod",
+                        "unitsToday",
+                      ]
+                      "type" = "object"
+                    }
                   }
                   "required" = [
-                    "authToken",
                     "endpointUrl",
                   ]
                   "type" = "object"
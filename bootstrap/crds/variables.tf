@@ -0,0 +1,4 @@
+// Namespace of the operator, which serves the conversion webhook.
+variable "namespace" {
+  type = string
+}
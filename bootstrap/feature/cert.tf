@@ -18,3 +18,41 @@ resource "kubernetes_manifest" "certificate_cluster_wildcard_tls" {
   }
 }
 
+
+resource "kubernetes_manifest" "issuer_operator_webhook" {
+  manifest = {
+    "apiVersion" = "cert-manager.io/v1"
+    "kind"       = "Issuer"
+    "metadata" = {
+      "name"      = "operator-webhook"
+      "namespace" = var.namespace
+    }
+    "spec" = {
+      "selfSigned" = {}
+    }
+  }
+}
+
+// The API server trusts it through the CA injected by cert-manager into the webhook configurations.
+resource "kubernetes_manifest" "certificate_operator_webhook" {
+  manifest = {
+    "apiVersion" = "cert-manager.io/v1"
+    "kind"       = "Certificate"
+    "metadata" = {
+      "name"      = local.webhook_service_name
+      "namespace" = var.namespace
+    }
+    "spec" = {
+      "dnsNames" = [
+        "${local.webhook_service_name}.${var.namespace}.svc",
+        "${local.webhook_service_name}.${var.namespace}.svc.cluster.local",
+      ]
+
+      "issuerRef" = {
+        "kind" = "Issuer"
+        "name" = "operator-webhook"
+      }
+      "secretName" = local.webhook_cert_secret_name
+    }
+  }
+}
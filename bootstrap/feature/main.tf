@@ -19,6 +19,11 @@ variable "dns_names" {
   type = list(string)
 }
 
+variable "networks" {
+  description = "Networks served by the proxies, ports of other networks are rejected"
+  type        = list(string)
+}
+
 variable "cert_secret_name" {
   type    = string
   default = "trp-proxy-tls"
@@ -73,3 +78,8 @@ variable "resources" {
     }
   }
 }
+
+variable "tiers_config" {
+  description = "Tiers file of the proxies, read by the operator for tier validation and quotas"
+  type        = string
+}
@@ -1,6 +1,18 @@
 locals {
-  role = "operator"
-  port = 9946
+  role         = "operator"
+  port         = 9946
+  webhook_port = 9443
+}
+
+resource "kubernetes_config_map" "operator" {
+  metadata {
+    namespace = var.namespace
+    name      = "operator-config"
+  }
+
+  data = {
+    "tiers.toml" = var.tiers_config
+  }
 }
 
 resource "kubernetes_deployment_v1" "operator" {
@@ -45,6 +57,24 @@ resource "kubernetes_deployment_v1" "operator" {
             value = "true"
           }
 
+          env {
+            name = "POD_NAME"
+            value_from {
+              field_ref {
+                field_path = "metadata.name"
+              }
+            }
+          }
+
+          env {
+            name = "POD_NAMESPACE"
+            value_from {
+              field_ref {
+                field_path = "metadata.namespace"
+              }
+            }
+          }
+
           env {
             name  = "PROMETHEUS_URL"
             value = "http://prometheus-operated.demeter-system.svc.cluster.local:9090/api/v1"
@@ -60,6 +90,37 @@ resource "kubernetes_deployment_v1" "operator" {
             value = var.extension_domain
           }
 
+          env {
+            name  = "NETWORKS"
+            value = join(",", var.networks)
+          }
+
+          env {
+            name  = "TIERS_PATH"
+            value = "/configs/tiers.toml"
+          }
+
+          env {
+            name  = "WEBHOOK_CERT_PATH"
+            value = "/webhook-certs/tls.crt"
+          }
+
+          env {
+            name  = "WEBHOOK_KEY_PATH"
+            value = "/webhook-certs/tls.key"
+          }
+
+          volume_mount {
+            mount_path = "/configs"
+            name       = "configs"
+          }
+
+          volume_mount {
+            mount_path = "/webhook-certs"
+            name       = "webhook-certs"
+            read_only  = true
+          }
+
           resources {
             limits = {
               cpu    = var.resources.limits.cpu
@@ -76,6 +137,47 @@ resource "kubernetes_deployment_v1" "operator" {
             container_port = local.port
             protocol       = "TCP"
           }
+
+          port {
+            name           = "webhook"
+            container_port = local.webhook_port
+            protocol       = "TCP"
+          }
+
+          liveness_probe {
+            http_get {
+              path = "/healthz"
+              port = local.port
+            }
+          }
+
+          readiness_probe {
+            http_get {
+              path = "/readyz"
+              port = local.port
+            }
+          }
+
+          startup_probe {
+            http_get {
+              path = "/readyz/webhook"
+              port = local.port
+            }
+          }
+        }
+
+        volume {
+          name = "configs"
+          config_map {
+            name = kubernetes_config_map.operator.metadata.0.name
+          }
+        }
+
+        volume {
+          name = "webhook-certs"
+          secret {
+            secret_name = local.webhook_cert_secret_name
+          }
         }
 
         dynamic "toleration" {
@@ -0,0 +1,68 @@
+locals {
+  webhook_service_name     = "operator-webhook"
+  webhook_cert_secret_name = "operator-webhook-tls"
+}
+
+resource "kubernetes_service_v1" "operator_webhook" {
+  metadata {
+    name      = local.webhook_service_name
+    namespace = var.namespace
+  }
+
+  spec {
+    selector = {
+      role = local.role
+    }
+
+    port {
+      name        = "webhook"
+      port        = 443
+      target_port = local.webhook_port
+      protocol    = "TCP"
+    }
+
+    type = "ClusterIP"
+
+    // The leader isn't ready while its usage is stale, but it keeps serving admission requests.
+    // The webhook listener is checked by the startup probe on /readyz/webhook instead.
+    publish_not_ready_addresses = true
+  }
+}
+
+resource "kubernetes_validating_webhook_configuration_v1" "operator" {
+  metadata {
+    name = "trpports.demeter.run"
+    annotations = {
+      "cert-manager.io/inject-ca-from" = "${var.namespace}/${local.webhook_service_name}"
+    }
+  }
+
+  webhook {
+    name                      = "trpports.demeter.run"
+    admission_review_versions = ["v1"]
+    side_effects              = "None"
+    failure_policy            = "Fail"
+
+    client_config {
+      service {
+        name      = local.webhook_service_name
+        namespace = var.namespace
+        path      = "/validate"
+        port      = 443
+      }
+    }
+
+    rule {
+      api_groups   = ["demeter.run"]
+      api_versions = ["v1alpha1", "v1beta1"]
+      operations   = ["CREATE", "UPDATE"]
+      resources    = ["trpports"]
+      scope        = "Namespaced"
+    }
+  }
+
+  // Filled in by cert-manager.
+  lifecycle {
+    ignore_changes = [webhook[0].client_config[0].ca_bundle]
+  }
+}
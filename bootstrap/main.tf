@@ -25,6 +25,8 @@ module "feature" {
   tolerations        = var.operator_tolerations
   extension_domain   = var.extension_domain
   dns_names          = var.dns_names
+  networks           = var.networks
+  tiers_config       = module.proxies[var.networks[0]].tiers_config
 }
 
 module "proxies" {
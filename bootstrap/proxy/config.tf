@@ -2,7 +2,9 @@
 locals {
   tiers = [
     {
-      "name" = "0",
+      "name"            = "0",
+      "allowed_methods" = ["trp.resolve"],
+      "max_batch_size"  = 10,
       "rates" = [
         {
           "interval" = "1m",
@@ -15,7 +17,8 @@ locals {
       ]
     },
     {
-      "name" = "1",
+      "name"           = "1",
+      "max_batch_size" = 20,
       "rates" = [
         {
           "interval" = "1m",
@@ -28,7 +31,8 @@ locals {
       ]
     },
     {
-      "name" = "2",
+      "name"           = "2",
+      "max_batch_size" = 50,
       "rates" = [
         {
           "interval" = "1m",
@@ -41,7 +45,8 @@ locals {
       ]
     },
     {
-      "name" = "3",
+      "name"           = "3",
+      "max_batch_size" = 100,
       "rates" = [
         {
           "interval" = "1m",
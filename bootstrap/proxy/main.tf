@@ -82,3 +82,7 @@ variable "cert_secret_name" {
   type    = string
   default = "trp-proxy-tls"
 }
+
+output "tiers_config" {
+  value = kubernetes_config_map.proxy.data["tiers.toml"]
+}
@@ -0,0 +1,128 @@
+use chrono::Utc;
+use k8s_openapi::{
+    api::core::v1::Secret, apimachinery::pkg::apis::meta::v1::ObjectMeta, ByteString,
+};
+use kube::{
+    api::{Patch, PatchParams},
+    Api, Client, Resource, ResourceExt,
+};
+use rand::distributions::{Alphanumeric, DistString};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use tracing::warn;
+
+use crate::{get_config, Result, TrpPort};
+
+pub static TRP_PORT_LABEL: &str = "demeter.run/trp-port";
+pub static AUTH_TOKEN_SECRET_KEY: &str = "token";
+pub static AUTH_KEY_SECRET_PREFIX: &str = "key.";
+
+static FIELD_MANAGER: &str = "trp-operator";
+static AUTH_TOKEN_LENGTH: usize = 48;
+
+pub fn build_auth_secret_name(port_name: &str) -> String {
+    format!("{port_name}-trp-auth")
+}
+
+pub fn generate_auth_token() -> String {
+    Alphanumeric.sample_string(&mut rand::thread_rng(), AUTH_TOKEN_LENGTH)
+}
+
+/// Entry of the port Secret holding the token of a rotation key.
+pub fn build_auth_key_entry(secret_name: &str) -> String {
+    format!("{AUTH_KEY_SECRET_PREFIX}{secret_name}")
+}
+
+/// Hashes an api key so that only its digest is kept in memory or shown in the status. Lookups
+/// compare fixed-size digests, so their cost doesn't depend on how much of a guessed key matches
+/// a live one.
+pub fn hash_key(key: &str) -> String {
+    format!("{:x}", Sha256::digest(key.as_bytes()))
+}
+
+pub fn read_auth_token(secret: &Secret) -> Option<String> {
+    read_secret_entry(secret, AUTH_TOKEN_SECRET_KEY)
+}
+
+/// Token of the rotation key stored from the Secret `secret_name`.
+pub fn read_auth_key(secret: &Secret, secret_name: &str) -> Option<String> {
+    read_secret_entry(secret, &build_auth_key_entry(secret_name))
+}
+
+fn read_secret_entry(secret: &Secret, key: &str) -> Option<String> {
+    let value = secret.data.as_ref()?.get(key)?;
+    String::from_utf8(value.0.clone()).ok()
+}
+
+/// Writes the port auth token to its owned Secret and returns the Secret. The token comes from
+/// the spec when set, otherwise the one already stored is kept, or a new one is generated. The
+/// tokens of the rotation keys are copied from their Secrets, so the proxy only watches the
+/// Secrets of the operator. Rotation keys whose Secret is missing or too short are left out and
+/// their Secret names returned.
+pub async fn provision_auth_secret(client: Client, crd: &TrpPort) -> Result<(Secret, Vec<String>)> {
+    let namespace = crd.namespace().unwrap();
+    let name = build_auth_secret_name(&crd.name_any());
+    let api: Api<Secret> = Api::namespaced(client, &namespace);
+    let min_length = get_config().min_auth_token_length;
+
+    let token = match &crd.spec.auth_token {
+        Some(token) if !token.is_empty() => token.clone(),
+        _ => api
+            .get_opt(&name)
+            .await?
+            .and_then(|secret| read_auth_token(&secret))
+            .unwrap_or_else(generate_auth_token),
+    };
+
+    let mut data = BTreeMap::from([(
+        AUTH_TOKEN_SECRET_KEY.to_string(),
+        ByteString(token.into_bytes()),
+    )]);
+    let mut missing = Vec::new();
+    let now = Utc::now();
+    for key in crd.spec.auth_keys.iter().filter(|key| !key.is_expired(now)) {
+        let rotation_token = api
+            .get_opt(&key.secret_name)
+            .await?
+            .and_then(|secret| read_auth_token(&secret))
+            .filter(|token| token.chars().count() >= min_length);
+
+        match rotation_token {
+            Some(rotation_token) => {
+                data.insert(
+                    build_auth_key_entry(&key.secret_name),
+                    ByteString(rotation_token.into_bytes()),
+                );
+            }
+            None => {
+                warn!(
+                    secret = key.secret_name.as_str(),
+                    "rotation key secret missing or invalid"
+                );
+                missing.push(key.secret_name.clone());
+            }
+        }
+    }
+
+    let secret = Secret {
+        metadata: ObjectMeta {
+            name: Some(name.clone()),
+            namespace: Some(namespace),
+            labels: Some(BTreeMap::from([(
+                TRP_PORT_LABEL.to_string(),
+                crd.name_any(),
+            )])),
+            owner_references: crd.controller_owner_ref(&()).map(|owner| vec![owner]),
+            ..Default::default()
+        },
+        data: Some(data),
+        type_: Some("Opaque".into()),
+        ..Default::default()
+    };
+
+    let patch_params = PatchParams::apply(FIELD_MANAGER).force();
+    api.patch(&name, &patch_params, &Patch::Apply(&secret))
+        .await?;
+
+    Ok((secret, missing))
+}
@@ -0,0 +1,88 @@
+use chrono::{DateTime, Utc};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::Validation;
+
+pub static CONDITION_READY: &str = "Ready";
+pub static CONDITION_NETWORK_AVAILABLE: &str = "NetworkAvailable";
+pub static CONDITION_TIER_VALID: &str = "TierValid";
+pub static CONDITION_KEY_PROVISIONED: &str = "KeyProvisioned";
+pub static CONDITION_QUOTA_EXCEEDED: &str = "QuotaExceeded";
+pub static CONDITION_SUSPENDED: &str = "Suspended";
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, JsonSchema)]
+pub enum ConditionStatus {
+    True,
+    False,
+    Unknown,
+}
+
+/// Follows the fields of the standard Kubernetes condition, so `kubectl wait --for=condition=`
+/// works on ports.
+#[derive(Deserialize, Serialize, Clone, Debug, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct TrpPortCondition {
+    #[serde(rename = "type")]
+    pub type_: String,
+    pub status: ConditionStatus,
+    pub reason: String,
+    pub message: String,
+    pub last_transition_time: DateTime<Utc>,
+    pub observed_generation: Option<i64>,
+}
+
+impl TrpPortCondition {
+    pub fn new(type_: &str, status: ConditionStatus, reason: &str, message: String) -> Self {
+        Self {
+            type_: type_.to_string(),
+            status,
+            reason: reason.to_string(),
+            message,
+            last_transition_time: Utc::now(),
+            observed_generation: None,
+        }
+    }
+
+    pub fn from_validation(type_: &str, validation: Validation) -> Self {
+        Self::new(
+            type_,
+            validation.status,
+            validation.reason,
+            validation.message,
+        )
+    }
+
+    pub fn is(&self, status: ConditionStatus) -> bool {
+        self.status == status
+    }
+}
+
+/// Stamps the conditions with the generation they were computed for, keeping the transition
+/// time of the conditions whose status didn't change.
+pub fn merge_conditions(
+    previous: &[TrpPortCondition],
+    conditions: Vec<TrpPortCondition>,
+    generation: Option<i64>,
+) -> Vec<TrpPortCondition> {
+    conditions
+        .into_iter()
+        .map(|mut condition| {
+            if let Some(existing) = previous
+                .iter()
+                .find(|c| c.type_ == condition.type_ && c.status == condition.status)
+            {
+                condition.last_transition_time = existing.last_transition_time;
+            }
+            condition.observed_generation = generation;
+            condition
+        })
+        .collect()
+}
+
+pub fn find_condition<'a>(
+    conditions: &'a [TrpPortCondition],
+    type_: &str,
+) -> Option<&'a TrpPortCondition> {
+    conditions.iter().find(|c| c.type_ == type_)
+}
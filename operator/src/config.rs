@@ -1,10 +1,20 @@
 use lazy_static::lazy_static;
-use std::{env, time::Duration};
+use std::{env, path::PathBuf, time::Duration};
 
 lazy_static! {
     static ref CONTROLLER_CONFIG: Config = Config::from_env();
 }
 
+/// Usage units per consumer in a collection window. `$STATUS_CODES` is replaced by the non billable
+/// status codes regex, `$SELECTOR` by the extra label matchers, `$WINDOW` by the window range and
+/// `$END` by the window end timestamp. The result must be grouped by consumer, network and tier.
+///
+/// Units are only emitted by proxies that weight requests by method cost. Consumers without units
+/// in the window, like windows backfilled from before the proxies were rolled out, are counted
+/// by requests instead. Rejected requests emit no units, so the fallback only counts requests
+/// without a rejection reason, or from proxies that didn't label it yet.
+static DEFAULT_USAGE_QUERY: &str = "sum by (consumer, network, tier) (increase(trp_proxy_http_total_units{status_code!~\"$STATUS_CODES\"$SELECTOR}[$WINDOW] @ $END)) or sum by (consumer, network, tier) (increase(trp_proxy_http_total_request{status_code!~\"$STATUS_CODES\",reason=~\"none|\"$SELECTOR}[$WINDOW] @ $END))";
+
 pub fn get_config() -> &'static Config {
     &CONTROLLER_CONFIG
 }
@@ -14,7 +24,38 @@ pub struct Config {
     pub dns_zone: String,
     pub extension_subdomain: String,
     pub metrics_delay: Duration,
+    pub metrics_backfill_chunk: Duration,
+    pub metrics_max_backfill: Duration,
+    pub readiness_usage_intervals: u32,
     pub prometheus_url: String,
+    pub usage_query: String,
+    pub usage_excluded_status_codes: String,
+    pub namespace: Option<String>,
+    pub usage_state_config_map: String,
+    pub usage_series_grace_period: Duration,
+    pub usage_status_interval: Duration,
+    pub usage_status_patch_delay: Duration,
+    pub project_label: String,
+    pub usage_sink: Option<UsageSinkKind>,
+    pub usage_sink_retries: u32,
+    pub usage_sink_timeout: Duration,
+    pub usage_spool_path: Option<PathBuf>,
+    pub networks: Vec<String>,
+    pub tiers_path: Option<PathBuf>,
+    pub min_auth_token_length: usize,
+    pub webhook_addr: String,
+    pub webhook_cert_path: Option<PathBuf>,
+    pub webhook_key_path: Option<PathBuf>,
+    pub webhook_self_signed: bool,
+    pub lease_name: String,
+    pub lease_identity: String,
+    pub lease_duration: Duration,
+}
+
+#[derive(Debug, Clone)]
+pub enum UsageSinkKind {
+    File(PathBuf),
+    Webhook(String),
 }
 
 impl Config {
@@ -28,7 +69,113 @@ impl Config {
                     .parse::<u64>()
                     .expect("METRICS_DELAY must be a number"),
             ),
+            metrics_backfill_chunk: Duration::from_secs(
+                env::var("METRICS_BACKFILL_CHUNK")
+                    .map(|v| {
+                        v.parse::<u64>()
+                            .expect("METRICS_BACKFILL_CHUNK must be a number")
+                    })
+                    .unwrap_or(60 * 60),
+            ),
+            metrics_max_backfill: Duration::from_secs(
+                env::var("METRICS_MAX_BACKFILL")
+                    .map(|v| {
+                        v.parse::<u64>()
+                            .expect("METRICS_MAX_BACKFILL must be a number")
+                    })
+                    .unwrap_or(7 * 24 * 60 * 60),
+            ),
+            readiness_usage_intervals: env::var("READINESS_USAGE_INTERVALS")
+                .map(|v| {
+                    v.parse::<u32>()
+                        .expect("READINESS_USAGE_INTERVALS must be a number")
+                })
+                .unwrap_or(3),
             prometheus_url: env::var("PROMETHEUS_URL").expect("PROMETHEUS_URL must be set"),
+            usage_query: env::var("USAGE_QUERY").unwrap_or(DEFAULT_USAGE_QUERY.into()),
+            usage_excluded_status_codes: env::var("USAGE_EXCLUDED_STATUS_CODES")
+                .unwrap_or("400|401|403|413|429|503".into()),
+            namespace: env::var("POD_NAMESPACE").ok(),
+            usage_state_config_map: env::var("USAGE_STATE_CONFIG_MAP")
+                .unwrap_or("trp-operator-usage".into()),
+            usage_series_grace_period: Duration::from_secs(
+                env::var("USAGE_SERIES_GRACE_PERIOD")
+                    .map(|v| {
+                        v.parse::<u64>()
+                            .expect("USAGE_SERIES_GRACE_PERIOD must be a number")
+                    })
+                    .unwrap_or(5 * 60),
+            ),
+            usage_status_interval: Duration::from_secs(
+                env::var("USAGE_STATUS_INTERVAL")
+                    .map(|v| {
+                        v.parse::<u64>()
+                            .expect("USAGE_STATUS_INTERVAL must be a number")
+                    })
+                    .unwrap_or(5 * 60),
+            ),
+            usage_status_patch_delay: Duration::from_millis(
+                env::var("USAGE_STATUS_PATCH_DELAY")
+                    .map(|v| {
+                        v.parse::<u64>()
+                            .expect("USAGE_STATUS_PATCH_DELAY must be a number")
+                    })
+                    .unwrap_or(100),
+            ),
+            project_label: env::var("PROJECT_LABEL").unwrap_or("demeter.run/project".into()),
+            usage_sink: env::var("USAGE_SINK").ok().map(|kind| match kind.as_str() {
+                "file" => UsageSinkKind::File(
+                    env::var("USAGE_SINK_PATH")
+                        .expect("USAGE_SINK_PATH must be set")
+                        .into(),
+                ),
+                "webhook" => UsageSinkKind::Webhook(
+                    env::var("USAGE_SINK_URL").expect("USAGE_SINK_URL must be set"),
+                ),
+                _ => panic!("USAGE_SINK must be file or webhook"),
+            }),
+            usage_sink_retries: env::var("USAGE_SINK_RETRIES")
+                .map(|v| {
+                    v.parse::<u32>()
+                        .expect("USAGE_SINK_RETRIES must be a number")
+                })
+                .unwrap_or(3),
+            usage_sink_timeout: Duration::from_secs(
+                env::var("USAGE_SINK_TIMEOUT")
+                    .map(|v| {
+                        v.parse::<u64>()
+                            .expect("USAGE_SINK_TIMEOUT must be a number")
+                    })
+                    .unwrap_or(30),
+            ),
+            usage_spool_path: env::var("USAGE_SPOOL_PATH").map(|v| v.into()).ok(),
+            networks: env::var("NETWORKS")
+                .unwrap_or("mainnet,preprod,preview".into())
+                .split(',')
+                .map(|network| network.trim().to_string())
+                .collect(),
+            tiers_path: env::var("TIERS_PATH").map(|v| v.into()).ok(),
+            min_auth_token_length: env::var("MIN_AUTH_TOKEN_LENGTH")
+                .map(|v| {
+                    v.parse::<usize>()
+                        .expect("MIN_AUTH_TOKEN_LENGTH must be a number")
+                })
+                .unwrap_or(16),
+            webhook_addr: env::var("WEBHOOK_ADDR").unwrap_or("0.0.0.0:9443".into()),
+            webhook_cert_path: env::var("WEBHOOK_CERT_PATH").map(|v| v.into()).ok(),
+            webhook_key_path: env::var("WEBHOOK_KEY_PATH").map(|v| v.into()).ok(),
+            webhook_self_signed: env::var("WEBHOOK_SELF_SIGNED")
+                .map(|v| v == "true")
+                .unwrap_or(false),
+            lease_name: env::var("LEASE_NAME").unwrap_or("trp-operator".into()),
+            lease_identity: env::var("POD_NAME")
+                .or_else(|_| env::var("HOSTNAME"))
+                .unwrap_or("trp-operator".into()),
+            lease_duration: Duration::from_secs(
+                env::var("LEASE_DURATION")
+                    .map(|v| v.parse::<u64>().expect("LEASE_DURATION must be a number"))
+                    .unwrap_or(15),
+            ),
         }
     }
 }
@@ -1,24 +1,86 @@
+use chrono::{DateTime, Datelike, Months, NaiveTime, Utc};
 use futures::StreamExt;
+use k8s_openapi::api::core::v1::{ConfigMap, Secret};
 use kube::{
-    runtime::{controller::Action, watcher::Config as WatcherConfig, Controller},
-    Api, Client, CustomResource, CustomResourceExt, ResourceExt,
+    api::{DeleteParams, ListParams},
+    runtime::{
+        controller::Action,
+        events::{Event, EventType, Recorder, Reporter},
+        finalizer::{finalizer, Event as Finalizer},
+        watcher::Config as WatcherConfig,
+        Controller,
+    },
+    Api, Client, CustomResource, CustomResourceExt, Resource, ResourceExt,
 };
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use std::{sync::Arc, time::Duration};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 use tracing::{error, info, instrument};
 
-use crate::{build_hostname, patch_resource_status, Error, Metrics, Result, State};
+use crate::{
+    build_auth_secret_name, build_consumer_name, build_hostname, find_condition,
+    flush_consumer_usage, get_config, hash_key, merge_conditions, patch_resource_status,
+    provision_auth_secret, quota_condition, read_auth_key, read_auth_token, validate_network,
+    validate_tier, ConditionStatus, Error, Result, State, TrpPortCondition,
+    CONDITION_KEY_PROVISIONED, CONDITION_NETWORK_AVAILABLE, CONDITION_QUOTA_EXCEEDED,
+    CONDITION_READY, CONDITION_SUSPENDED, CONDITION_TIER_VALID, TRP_PORT_LABEL,
+};
 
 pub static TRP_PORT_FINALIZER: &str = "trpports.demeter.run";
+const AUTH_KEY_HINT_LENGTH: usize = 8;
+const MISSING_KEY_RETRY: Duration = Duration::from_secs(60);
 
 struct Context {
     pub client: Client,
-    pub metrics: Metrics,
+    pub state: Arc<State>,
+    pub recorder: Recorder,
+    // Last event published for each port, by uid.
+    published_events: Mutex<HashMap<String, (String, String)>>,
 }
 impl Context {
-    pub fn new(client: Client, metrics: Metrics) -> Self {
-        Self { client, metrics }
+    pub fn new(client: Client, state: Arc<State>) -> Self {
+        let reporter = Reporter {
+            controller: "trp-operator".into(),
+            instance: Some(get_config().lease_identity.clone()),
+        };
+        let recorder = Recorder::new(client.clone(), reporter);
+
+        Self {
+            client,
+            state,
+            recorder,
+            published_events: Mutex::default(),
+        }
+    }
+
+    /// Publishes an event on the port, unless it repeats the last event of the port. Requeues
+    /// would otherwise publish the same event over and over.
+    async fn publish_event(&self, crd: &TrpPort, type_: EventType, reason: &str, note: String) {
+        let uid = crd.uid().unwrap_or_default();
+        let published = (reason.to_string(), note.clone());
+        if self.published_events.lock().unwrap().get(&uid) == Some(&published) {
+            return;
+        }
+
+        let event = Event {
+            type_,
+            reason: reason.into(),
+            note: Some(note),
+            action: "Reconcile".into(),
+            secondary: None,
+        };
+
+        // Only published events are recorded, so a failed one is retried on the next reconcile.
+        match self.recorder.publish(&event, &crd.object_ref(&())).await {
+            Ok(()) => {
+                self.published_events.lock().unwrap().insert(uid, published);
+            }
+            Err(err) => error!(error = err.to_string(), "failed to publish event"),
+        }
     }
 }
 
@@ -26,7 +88,7 @@ impl Context {
 #[kube(
     kind = "TrpPort",
     group = "demeter.run",
-    version = "v1alpha1",
+    version = "v1beta1",
     shortname = "trpports",
     category = "demeter-port",
     namespaced
@@ -36,48 +98,413 @@ impl Context {
         {"name": "Network", "jsonPath": ".spec.network", "type": "string"},
         {"name": "Throughput Tier", "jsonPath":".spec.throughputTier", "type": "string"}, 
         {"name": "Endpoint URL", "jsonPath": ".status.endpointUrl", "type": "string"},
-        {"name": "Auth Token", "jsonPath": ".status.authToken", "type": "string"}
+        {"name": "Auth Secret", "jsonPath": ".status.authTokenSecret", "type": "string"},
+        {"name": "Ready", "jsonPath": ".status.conditions[?(@.type==\"Ready\")].status", "type": "string"}
     "#)]
 #[serde(rename_all = "camelCase")]
 pub struct TrpPortSpec {
     pub network: String,
     pub throughput_tier: String,
-    pub auth_token: String,
+    /// Left unset to let the operator generate one. The effective token is always stored in the
+    /// Secret referenced by the status.
+    pub auth_token: Option<String>,
+    /// Extra keys accepted for the port, used to rotate the auth token without downtime. Their
+    /// tokens are read from Secrets, never from the spec.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub auth_keys: Vec<TrpPortAuthKey>,
+    /// Units allowed per billing period, overriding the quota of the tier. Requests are weighted
+    /// by the method costs of the tier, as the proxy rate limiter does.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub monthly_quota: Option<u64>,
+    /// Keeps the port and its keys, but the proxy denies its requests.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub suspended: bool,
+    /// Shown to the clients of a suspended port.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub suspend_reason: Option<String>,
+}
+impl TrpPortSpec {
+    /// Keys accepted at `now`: the auth token read from the port Secret and every extra key that
+    /// hasn't expired yet, with its token copied into the port Secret. Extra keys missing from
+    /// the Secret are left out.
+    pub fn active_keys(
+        &self,
+        auth_token: Option<&str>,
+        secret: Option<&Secret>,
+        now: DateTime<Utc>,
+    ) -> Vec<AuthKey> {
+        let primary = auth_token.map(|token| AuthKey {
+            token: token.to_string(),
+            not_after: None,
+        });
+        let rotation = self
+            .auth_keys
+            .iter()
+            .filter(|key| !key.is_expired(now))
+            .filter_map(|key| {
+                Some(AuthKey {
+                    token: read_auth_key(secret?, &key.secret_name)?,
+                    not_after: key.not_after,
+                })
+            });
+
+        primary.into_iter().chain(rotation).collect()
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct TrpPortAuthKey {
+    /// Secret in the namespace of the port holding the key under `token`.
+    pub secret_name: String,
+    pub not_after: Option<DateTime<Utc>>,
+}
+impl TrpPortAuthKey {
+    pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        self.not_after.is_some_and(|not_after| not_after <= now)
+    }
 }
 
 #[derive(Deserialize, Serialize, Clone, Default, Debug, JsonSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct TrpPortStatus {
     pub endpoint_url: String,
-    pub auth_token: String,
+    /// Name of the Secret holding the auth token.
+    #[serde(default)]
+    pub auth_token_secret: String,
+    #[serde(default)]
+    pub auth_keys: Vec<TrpPortAuthKeyStatus>,
+    #[serde(default)]
+    pub conditions: Vec<TrpPortCondition>,
+    pub observed_generation: Option<i64>,
+    /// Written by the usage collector, the reconciler leaves it untouched.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub usage: Option<TrpPortUsage>,
+}
+
+/// Units used by the port, counted in UTC days and calendar month billing periods. Requests are
+/// weighted by the method costs of the tier, as the proxy rate limiter does.
+#[derive(Deserialize, Serialize, Clone, Debug, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct TrpPortUsage {
+    pub day_start: DateTime<Utc>,
+    pub units_today: u64,
+    pub billing_period_start: DateTime<Utc>,
+    pub units_billing_period: u64,
+    pub last_updated: DateTime<Utc>,
+}
+impl TrpPortUsage {
+    /// Adds units of the window from `start` to `end`, restarting the counts of a day or billing
+    /// period that ended since the previous update. Windows are charged to the day they start in,
+    /// so a window ending at midnight counts for the day before.
+    pub fn add(
+        previous: Option<&Self>,
+        units: u64,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Self {
+        let today = start.date_naive();
+        let day_start = today.and_time(NaiveTime::MIN).and_utc();
+        let billing_period_start = today
+            .with_day(1)
+            .unwrap()
+            .and_time(NaiveTime::MIN)
+            .and_utc();
+
+        let units_today = match previous {
+            Some(previous) if previous.day_start == day_start => previous.units_today + units,
+            _ => units,
+        };
+        let units_billing_period = match previous {
+            Some(previous) if previous.billing_period_start == billing_period_start => {
+                previous.units_billing_period + units
+            }
+            _ => units,
+        };
+
+        Self {
+            day_start,
+            units_today,
+            billing_period_start,
+            units_billing_period,
+            last_updated: end,
+        }
+    }
+
+    pub fn billing_period_end(&self) -> DateTime<Utc> {
+        self.billing_period_start + Months::new(1)
+    }
+}
+
+impl TrpPortStatus {
+    /// End of the billing period in which the port ran out of quota, the proxy rejects the
+    /// requests of the port until then.
+    pub fn quota_exceeded_until(&self) -> Option<DateTime<Utc>> {
+        find_condition(&self.conditions, CONDITION_QUOTA_EXCEEDED)
+            .filter(|condition| condition.is(ConditionStatus::True))?;
+        Some(self.usage.as_ref()?.billing_period_end())
+    }
+}
+
+/// Key accepted for a port, with its token resolved from the port Secret.
+#[derive(Clone, Debug)]
+pub struct AuthKey {
+    pub token: String,
+    pub not_after: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct TrpPortAuthKeyStatus {
+    /// Start of the SHA-256 hash of the key, as indexed by the proxy.
+    pub hint: String,
+    pub state: TrpPortAuthKeyState,
+    pub not_after: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, JsonSchema)]
+pub enum TrpPortAuthKeyState {
+    Active,
+    Expiring,
+}
+
+impl From<&AuthKey> for TrpPortAuthKeyStatus {
+    fn from(value: &AuthKey) -> Self {
+        let hint = hash_key(&value.token)[..AUTH_KEY_HINT_LENGTH].to_string();
+        let state = match value.not_after {
+            Some(_) => TrpPortAuthKeyState::Expiring,
+            None => TrpPortAuthKeyState::Active,
+        };
+
+        Self {
+            hint,
+            state,
+            not_after: value.not_after,
+        }
+    }
 }
 
 async fn reconcile(crd: Arc<TrpPort>, ctx: Arc<Context>) -> Result<Action> {
+    let namespace = crd.namespace().unwrap();
+    let api: Api<TrpPort> = Api::namespaced(ctx.client.clone(), &namespace);
+
+    finalizer(&api, TRP_PORT_FINALIZER, crd, |event| async {
+        match event {
+            Finalizer::Apply(crd) => apply(crd, ctx.clone()).await,
+            Finalizer::Cleanup(crd) => cleanup(crd, ctx.clone()).await,
+        }
+    })
+    .await
+    .map_err(|err| Error::FinalizerError(Box::new(err)))
+}
+
+async fn apply(crd: Arc<TrpPort>, ctx: Arc<Context>) -> Result<Action> {
+    let (secret, missing_keys) = provision_auth_secret(ctx.client.clone(), &crd).await?;
+
+    let auth_token_secret = build_auth_secret_name(&crd.name_any());
+
+    let now = Utc::now();
+    let auth_token = read_auth_token(&secret);
+    let active_keys = crd
+        .spec
+        .active_keys(auth_token.as_deref(), Some(&secret), now);
+
+    let mut conditions = vec![
+        TrpPortCondition::from_validation(
+            CONDITION_NETWORK_AVAILABLE,
+            validate_network(&crd.spec.network),
+        ),
+        TrpPortCondition::from_validation(
+            CONDITION_TIER_VALID,
+            validate_tier(&crd.spec.throughput_tier),
+        ),
+        TrpPortCondition::new(
+            CONDITION_KEY_PROVISIONED,
+            ConditionStatus::True,
+            "SecretProvisioned",
+            format!("Auth token stored in secret {auth_token_secret}"),
+        ),
+        suspended_condition(&crd.spec),
+    ];
+    let ready = ready_condition(&conditions);
+    conditions.push(ready.clone());
+
+    // Running out of quota doesn't make the port unready, the proxy rejects its requests until
+    // the billing period ends.
+    let usage = crd.status.as_ref().and_then(|status| status.usage.as_ref());
+    let quota = quota_condition(&crd.spec, usage, now);
+    let quota_exceeded_until = usage
+        .filter(|_| quota.is(ConditionStatus::True))
+        .map(|usage| usage.billing_period_end());
+    conditions.push(quota);
+
+    let previous_conditions = crd
+        .status
+        .as_ref()
+        .map(|status| status.conditions.as_slice())
+        .unwrap_or_default();
+    let generation = crd.metadata.generation;
+
+    let endpoint_url = format!("https://{}", build_hostname(&crd.spec.network));
+
     let status = TrpPortStatus {
-        endpoint_url: format!("https://{}", build_hostname(&crd.spec.network)),
-        auth_token: crd.spec.auth_token.clone(),
+        endpoint_url: endpoint_url.clone(),
+        auth_token_secret,
+        auth_keys: active_keys.iter().map(TrpPortAuthKeyStatus::from).collect(),
+        conditions: merge_conditions(previous_conditions, conditions, generation),
+        observed_generation: generation,
+        usage: None,
     };
 
     let namespace = crd.namespace().unwrap();
     let trp_port = TrpPort::api_resource();
 
+    let mut payload = serde_json::to_value(status)?;
+    // Earlier versions copied the token into the status, drop it.
+    payload["authToken"] = serde_json::Value::Null;
+
     patch_resource_status(
         ctx.client.clone(),
         &namespace,
         trp_port,
         &crd.name_any(),
-        serde_json::to_value(status)?,
+        payload,
     )
     .await?;
 
+    if ready.is(ConditionStatus::True) {
+        ctx.publish_event(
+            &crd,
+            EventType::Normal,
+            "EndpointAssigned",
+            format!("Port available at {endpoint_url}"),
+        )
+        .await;
+    } else {
+        ctx.publish_event(
+            &crd,
+            EventType::Warning,
+            &ready.reason,
+            ready.message.clone(),
+        )
+        .await;
+    }
+
+    // Secrets of rotation keys aren't watched, so missing ones are looked up again later.
+    if !missing_keys.is_empty() {
+        ctx.publish_event(
+            &crd,
+            EventType::Warning,
+            "KeySecretMissing",
+            format!(
+                "Secrets of rotation keys missing or too short: {}",
+                missing_keys.join(", ")
+            ),
+        )
+        .await;
+    }
+
     info!(resource = crd.name_any(), "Reconcile completed");
 
+    // Refresh the status once the next rotation key expires or the exceeded quota resets.
+    let next_expiration = active_keys
+        .iter()
+        .filter_map(|key| key.not_after)
+        .chain(quota_exceeded_until)
+        .min();
+    let next_refresh = next_expiration
+        .and_then(|not_after| (not_after - now).to_std().ok())
+        .into_iter()
+        .chain((!missing_keys.is_empty()).then_some(MISSING_KEY_RETRY))
+        .min();
+    match next_refresh {
+        Some(duration) => Ok(Action::requeue(duration)),
+        None => Ok(Action::await_change()),
+    }
+}
+
+/// The port is ready when every condition holds and it isn't suspended. The tier can't always be
+/// checked, so only a tier known to be missing makes the port not ready.
+fn ready_condition(conditions: &[TrpPortCondition]) -> TrpPortCondition {
+    let failed = conditions.iter().find(|c| {
+        if c.type_ == CONDITION_SUSPENDED {
+            return c.is(ConditionStatus::True);
+        }
+        c.is(ConditionStatus::False)
+            || (c.type_ != CONDITION_TIER_VALID && c.is(ConditionStatus::Unknown))
+    });
+
+    match failed {
+        Some(condition) => TrpPortCondition::new(
+            CONDITION_READY,
+            ConditionStatus::False,
+            &condition.reason,
+            condition.message.clone(),
+        ),
+        None => TrpPortCondition::new(
+            CONDITION_READY,
+            ConditionStatus::True,
+            "PortReady",
+            "Port is ready to serve requests".into(),
+        ),
+    }
+}
+
+fn suspended_condition(spec: &TrpPortSpec) -> TrpPortCondition {
+    if spec.suspended {
+        return TrpPortCondition::new(
+            CONDITION_SUSPENDED,
+            ConditionStatus::True,
+            "PortSuspended",
+            spec.suspend_reason
+                .clone()
+                .unwrap_or("Port is suspended".into()),
+        );
+    }
+
+    TrpPortCondition::new(
+        CONDITION_SUSPENDED,
+        ConditionStatus::False,
+        "PortActive",
+        "Port is not suspended".into(),
+    )
+}
+
+/// Runs before the port is deleted. The finalizer is only removed once everything succeeds.
+async fn cleanup(crd: Arc<TrpPort>, ctx: Arc<Context>) -> Result<Action> {
+    let namespace = crd.namespace().unwrap();
+    let consumer = build_consumer_name(&namespace, &crd.name_any());
+
+    flush_consumer_usage(&ctx.state, &ctx.client, &consumer).await?;
+
+    let params = ListParams::default().labels(&format!("{TRP_PORT_LABEL}={}", crd.name_any()));
+    Api::<Secret>::namespaced(ctx.client.clone(), &namespace)
+        .delete_collection(&DeleteParams::default(), &params)
+        .await?;
+    Api::<ConfigMap>::namespaced(ctx.client.clone(), &namespace)
+        .delete_collection(&DeleteParams::default(), &params)
+        .await?;
+
+    ctx.published_events
+        .lock()
+        .unwrap()
+        .remove(&crd.uid().unwrap_or_default());
+
+    info!(resource = crd.name_any(), "Cleanup completed");
+
     Ok(Action::await_change())
 }
 
 fn error_policy(crd: Arc<TrpPort>, err: &Error, ctx: Arc<Context>) -> Action {
     error!(error = err.to_string(), "reconcile failed");
-    ctx.metrics.reconcile_failure(&crd, err);
+    ctx.state.metrics.reconcile_failure(&crd, err);
+
+    let note = err.to_string();
+    tokio::spawn(async move {
+        ctx.publish_event(&crd, EventType::Warning, "ReconcileFailed", note)
+            .await;
+    });
+
     Action::requeue(Duration::from_secs(5))
 }
 
@@ -90,13 +517,117 @@ pub async fn run(state: Arc<State>) {
         .expect("failed to create kube client");
 
     let crds = Api::<TrpPort>::all(client.clone());
+    let secrets = Api::<Secret>::all(client.clone());
 
-    let ctx = Context::new(client, state.metrics.clone());
+    let ctx = Context::new(client, state.clone());
 
-    Controller::new(crds, WatcherConfig::default().any_semantic())
+    let controller = Controller::new(crds, WatcherConfig::default().any_semantic())
+        .owns(secrets, WatcherConfig::default().labels(TRP_PORT_LABEL));
+
+    let store = controller.store();
+    tokio::spawn(async move {
+        if store.wait_until_ready().await.is_ok() {
+            info!("controller watcher synced");
+            state.set_watcher_synced();
+        }
+    });
+
+    controller
         .shutdown_on_signal()
         .run(reconcile, error_policy, Arc::new(ctx))
         .filter_map(|x| async move { std::result::Result::ok(x) })
         .for_each(|_| futures::future::ready(()))
         .await;
 }
+
+#[cfg(test)]
+mod tests {
+    use k8s_openapi::ByteString;
+
+    use super::*;
+    use crate::fixtures::at;
+
+    #[test]
+    fn resolves_rotation_keys_from_the_port_secret() {
+        let spec: TrpPortSpec = serde_json::from_value(serde_json::json!({
+            "network": "cardano-mainnet",
+            "throughputTier": "0",
+            "authKeys": [
+                { "secretName": "next", "notAfter": at(2, 1, 0) },
+                { "secretName": "expired", "notAfter": at(1, 1, 0) },
+                { "secretName": "missing", "notAfter": null },
+            ],
+        }))
+        .unwrap();
+        let secret = Secret {
+            data: Some(
+                [
+                    ("token", "token"),
+                    ("key.next", "next-token"),
+                    ("key.expired", "old-token"),
+                ]
+                .into_iter()
+                .map(|(key, value)| (key.to_string(), ByteString(value.into())))
+                .collect(),
+            ),
+            ..Default::default()
+        };
+
+        let keys = spec.active_keys(Some("token"), Some(&secret), at(1, 30, 0));
+        let tokens = keys
+            .iter()
+            .map(|key| key.token.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(tokens, ["token", "next-token"]);
+
+        let status = TrpPortAuthKeyStatus::from(&keys[1]);
+        assert_eq!(status.hint, hash_key("next-token")[..8]);
+        assert_eq!(status.state, TrpPortAuthKeyState::Expiring);
+    }
+
+    #[test]
+    fn adds_usage_of_the_same_day() {
+        let first = TrpPortUsage::add(None, 5, at(1, 30, 10), at(1, 30, 11));
+        let usage = TrpPortUsage::add(Some(&first), 3, at(1, 30, 11), at(1, 30, 12));
+
+        assert_eq!(usage.day_start, at(1, 30, 0));
+        assert_eq!(usage.units_today, 8);
+        assert_eq!(usage.billing_period_start, at(1, 1, 0));
+        assert_eq!(usage.units_billing_period, 8);
+        assert_eq!(usage.last_updated, at(1, 30, 12));
+    }
+
+    #[test]
+    fn restarts_the_day_on_rollover() {
+        let first = TrpPortUsage::add(None, 5, at(1, 30, 10), at(1, 30, 11));
+        let usage = TrpPortUsage::add(Some(&first), 3, at(1, 31, 0), at(1, 31, 1));
+
+        assert_eq!(usage.day_start, at(1, 31, 0));
+        assert_eq!(usage.units_today, 3);
+        assert_eq!(usage.units_billing_period, 8);
+    }
+
+    #[test]
+    fn restarts_the_billing_period_on_month_rollover() {
+        let first = TrpPortUsage::add(None, 5, at(1, 31, 10), at(1, 31, 11));
+        let usage = TrpPortUsage::add(Some(&first), 3, at(2, 1, 0), at(2, 1, 1));
+
+        assert_eq!(usage.day_start, at(2, 1, 0));
+        assert_eq!(usage.units_today, 3);
+        assert_eq!(usage.billing_period_start, at(2, 1, 0));
+        assert_eq!(usage.units_billing_period, 3);
+        assert_eq!(usage.billing_period_end(), at(3, 1, 0));
+    }
+
+    #[test]
+    fn charges_a_window_ending_at_midnight_to_the_day_before() {
+        let first = TrpPortUsage::add(None, 5, at(1, 31, 22), at(1, 31, 23));
+        let usage = TrpPortUsage::add(Some(&first), 3, at(1, 31, 23), at(2, 1, 0));
+
+        assert_eq!(usage.day_start, at(1, 31, 0));
+        assert_eq!(usage.units_today, 8);
+        assert_eq!(usage.billing_period_start, at(1, 1, 0));
+        assert_eq!(usage.units_billing_period, 8);
+        assert_eq!(usage.last_updated, at(2, 1, 0));
+    }
+}
@@ -1,8 +1,42 @@
-use kube::CustomResourceExt;
+use k8s_openapi::apiextensions_apiserver::pkg::apis::apiextensions::v1::{
+    CustomResourceConversion, ServiceReference, WebhookClientConfig, WebhookConversion,
+};
+use kube::{core::crd::merge_crds, CustomResourceExt};
+use std::collections::BTreeMap;
 
 fn main() {
-    print!(
-        "{}",
-        serde_yaml::to_string(&operator::TrpPort::crd()).unwrap()
+    let mut crd = merge_crds(
+        vec![operator::v1alpha1::TrpPort::crd(), operator::TrpPort::crd()],
+        "v1beta1",
     )
+    .unwrap();
+
+    // Without a webhook service the API server can only rewrite the apiVersion of objects.
+    if let (Ok(name), Ok(namespace)) = (
+        std::env::var("WEBHOOK_SERVICE_NAME"),
+        std::env::var("WEBHOOK_SERVICE_NAMESPACE"),
+    ) {
+        // cert-manager injects the CA of the certificate named after the service.
+        crd.metadata.annotations = Some(BTreeMap::from([(
+            "cert-manager.io/inject-ca-from".into(),
+            format!("{namespace}/{name}"),
+        )]));
+        crd.spec.conversion = Some(CustomResourceConversion {
+            strategy: "Webhook".into(),
+            webhook: Some(WebhookConversion {
+                client_config: Some(WebhookClientConfig {
+                    service: Some(ServiceReference {
+                        name,
+                        namespace,
+                        path: Some("/convert".into()),
+                        port: Some(443),
+                    }),
+                    ..Default::default()
+                }),
+                conversion_review_versions: vec!["v1".into()],
+            }),
+        });
+    }
+
+    print!("{}", serde_yaml::to_string(&crd).unwrap())
 }
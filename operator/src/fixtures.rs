@@ -0,0 +1,45 @@
+//! Helpers shared by the unit tests.
+
+use chrono::{DateTime, Duration, TimeZone, Utc};
+
+use crate::UsageRecord;
+
+/// Namespace of the port the usage records belong to.
+pub const NAMESPACE: &str = "prj-project";
+
+/// Windows of an hour and a tier `0` with a quota of 100 units, the config is only read once
+/// per process.
+pub fn init_config() {
+    static INIT: std::sync::Once = std::sync::Once::new();
+    INIT.call_once(|| {
+        let tiers_path = std::env::temp_dir().join(format!("tiers-{}.toml", std::process::id()));
+        std::fs::write(
+            &tiers_path,
+            "[[tiers]]\nname = \"0\"\nmonthly_quota = 100\n",
+        )
+        .unwrap();
+
+        std::env::set_var("METRICS_DELAY", "3600");
+        std::env::set_var("PROMETHEUS_URL", "http://localhost:9090/api/v1");
+        std::env::set_var("TIERS_PATH", tiers_path);
+    });
+}
+
+pub fn at(month: u32, day: u32, hour: u32) -> DateTime<Utc> {
+    Utc.with_ymd_and_hms(2026, month, day, hour, 0, 0).unwrap()
+}
+
+/// Usage of a port in the window of an hour from `start`.
+pub fn record(port_name: &str, start: DateTime<Utc>, units: u64) -> UsageRecord {
+    UsageRecord::new(
+        "TrpPort",
+        "project",
+        NAMESPACE,
+        port_name,
+        "0",
+        "mainnet",
+        start,
+        start + Duration::hours(1),
+        units,
+    )
+}
@@ -0,0 +1,163 @@
+use chrono::{Duration as ChronoDuration, Utc};
+use k8s_openapi::{
+    api::coordination::v1::{Lease, LeaseSpec},
+    apimachinery::pkg::apis::meta::v1::{MicroTime, ObjectMeta},
+};
+use kube::{api::PostParams, Api, Client};
+use std::sync::Arc;
+use tokio::time::{timeout_at, Instant};
+use tracing::{error, info, instrument, warn};
+
+use crate::{get_config, Error, Result, State};
+
+/// Keeps the operator lease. Only the holder reconciles ports and collects usage, the other
+/// replicas wait in standby and take over once the lease expires. Without a lease namespace
+/// configured the operator runs as the only replica and is always the leader.
+#[instrument("leader election run", skip_all)]
+pub fn run_leader_election(state: Arc<State>) {
+    tokio::spawn(async move {
+        let config = get_config();
+
+        let Some(namespace) = &config.namespace else {
+            info!("leader election disabled, running as leader");
+            state.set_leader(true);
+            return;
+        };
+
+        let client = Client::try_default()
+            .await
+            .expect("failed to create kube client");
+        let api: Api<Lease> = Api::namespaced(client, namespace);
+
+        let lease_duration = config.lease_duration;
+        let renew_interval = lease_duration / 3;
+        // The leader steps down before the lease can expire for the other replicas, including
+        // the time the last renewal took.
+        let renew_deadline = lease_duration - renew_interval;
+        let mut last_renew = Instant::now();
+
+        info!(
+            identity = config.lease_identity.as_str(),
+            "leader election running"
+        );
+
+        loop {
+            let attempt = Instant::now();
+            let result = if state.is_leader() {
+                timeout_at(last_renew + renew_deadline, try_acquire_lease(&api))
+                    .await
+                    .unwrap_or_else(|_| Err(Error::HttpError("lease update timed out".into())))
+            } else {
+                try_acquire_lease(&api).await
+            };
+
+            match result {
+                Ok(true) => {
+                    last_renew = attempt;
+                    if !state.is_leader() {
+                        info!(
+                            identity = config.lease_identity.as_str(),
+                            "leadership acquired"
+                        );
+                        state.set_leader(true);
+                    }
+                }
+                Ok(false) => {
+                    if state.is_leader() {
+                        error!("leadership lost to another replica");
+                        std::process::exit(1);
+                    }
+                }
+                Err(err) => {
+                    warn!(error = err.to_string(), "failed to update lease");
+                    if state.is_leader() && last_renew.elapsed() >= renew_deadline {
+                        error!("leadership lease not renewed before the deadline");
+                        std::process::exit(1);
+                    }
+                }
+            }
+
+            // A leader that failed to renew retries before its deadline.
+            let delay = if state.is_leader() {
+                renew_interval
+                    .min((last_renew + renew_deadline).saturating_duration_since(Instant::now()))
+            } else {
+                renew_interval
+            };
+            tokio::time::sleep(delay).await;
+        }
+    });
+}
+
+/// Takes the lease when it's free or expired, or renews it when already held. Returns whether
+/// this replica holds the lease.
+async fn try_acquire_lease(api: &Api<Lease>) -> Result<bool> {
+    let config = get_config();
+    let now = Utc::now();
+    let identity = config.lease_identity.clone();
+    let lease_duration_seconds = config.lease_duration.as_secs() as i32;
+
+    let Some(mut lease) = api.get_opt(&config.lease_name).await? else {
+        let lease = Lease {
+            metadata: ObjectMeta {
+                name: Some(config.lease_name.clone()),
+                ..Default::default()
+            },
+            spec: Some(LeaseSpec {
+                holder_identity: Some(identity),
+                lease_duration_seconds: Some(lease_duration_seconds),
+                acquire_time: Some(MicroTime(now)),
+                renew_time: Some(MicroTime(now)),
+                lease_transitions: Some(0),
+                ..Default::default()
+            }),
+        };
+
+        return match api.create(&PostParams::default(), &lease).await {
+            Ok(_) => Ok(true),
+            Err(kube::Error::Api(err)) if err.code == 409 => Ok(false),
+            Err(err) => Err(Error::from(err)),
+        };
+    };
+
+    let spec = lease.spec.clone().unwrap_or_default();
+    let is_holder = spec.holder_identity.as_deref() == Some(identity.as_str());
+    let duration = spec
+        .lease_duration_seconds
+        .unwrap_or(lease_duration_seconds);
+    let expired = match &spec.renew_time {
+        Some(renew_time) => renew_time.0 + ChronoDuration::seconds(duration.into()) < now,
+        None => true,
+    };
+
+    if !is_holder && !expired {
+        return Ok(false);
+    }
+
+    let transitions = spec.lease_transitions.unwrap_or_default();
+    let (acquire_time, lease_transitions) = if is_holder {
+        (spec.acquire_time.clone(), transitions)
+    } else {
+        (Some(MicroTime(now)), transitions + 1)
+    };
+
+    lease.spec = Some(LeaseSpec {
+        holder_identity: Some(identity),
+        lease_duration_seconds: Some(lease_duration_seconds),
+        acquire_time,
+        renew_time: Some(MicroTime(now)),
+        lease_transitions: Some(lease_transitions),
+        ..spec
+    });
+
+    // The resource version in the metadata makes the replace fail when another replica updated
+    // the lease in the meantime.
+    match api
+        .replace(&config.lease_name, &PostParams::default(), &lease)
+        .await
+    {
+        Ok(_) => Ok(true),
+        Err(kube::Error::Api(err)) if err.code == 409 => Ok(false),
+        Err(err) => Err(Error::from(err)),
+    }
+}
@@ -1,5 +1,13 @@
+use chrono::{DateTime, Utc};
+use k8s_openapi::api::core::v1::Namespace;
+use kube::runtime::reflector::Store;
 use prometheus::Registry;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
 use thiserror::Error;
+use tokio::sync::{Mutex, Notify};
 
 #[derive(Error, Debug)]
 pub enum Error {
@@ -14,11 +22,74 @@ pub enum Error {
 
     #[error("Config Error: {0}")]
     ConfigError(String),
+
+    #[error("Conversion Error: {0}")]
+    ConversionError(String),
+
+    #[error("Finalizer Error: {0}")]
+    FinalizerError(#[source] Box<kube::runtime::finalizer::Error<Error>>),
+
+    #[error("Prometheus Decode Error: {0}")]
+    PrometheusDecodeError(#[source] serde_json::Error),
+
+    #[error("Validation Error: {0}")]
+    ValidationError(String),
+
+    #[error("Usage Spool Error: {0}")]
+    SpoolError(#[source] std::io::Error),
+}
+
+/// Fixed set of error labels for metrics. The error details only go to logs and events.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorKind {
+    Kube,
+    Deserialize,
+    Http,
+    Config,
+    Conversion,
+    Finalizer,
+    PrometheusDecode,
+    Validation,
+    Spool,
+}
+impl ErrorKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorKind::Kube => "kube",
+            ErrorKind::Deserialize => "deserialize",
+            ErrorKind::Http => "http",
+            ErrorKind::Config => "config",
+            ErrorKind::Conversion => "conversion",
+            ErrorKind::Finalizer => "finalizer",
+            ErrorKind::PrometheusDecode => "prometheus_decode",
+            ErrorKind::Validation => "validation",
+            ErrorKind::Spool => "spool",
+        }
+    }
 }
 
 impl Error {
-    pub fn metric_label(&self) -> String {
-        format!("{self:?}").to_lowercase()
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Error::KubeError(_) => ErrorKind::Kube,
+            Error::DeserializeError(_) => ErrorKind::Deserialize,
+            Error::HttpError(_) => ErrorKind::Http,
+            Error::ConfigError(_) => ErrorKind::Config,
+            Error::ConversionError(_) => ErrorKind::Conversion,
+            // Errors of the apply and cleanup steps are labelled by their cause.
+            Error::FinalizerError(err) => match err.as_ref() {
+                kube::runtime::finalizer::Error::ApplyFailed(err)
+                | kube::runtime::finalizer::Error::CleanupFailed(err) => err.kind(),
+                _ => ErrorKind::Finalizer,
+            },
+            Error::PrometheusDecodeError(_) => ErrorKind::PrometheusDecode,
+            Error::ValidationError(_) => ErrorKind::Validation,
+            Error::SpoolError(_) => ErrorKind::Spool,
+        }
+    }
+
+    pub fn metric_label(&self) -> &'static str {
+        self.kind().as_str()
     }
 }
 
@@ -38,37 +109,146 @@ impl From<kube::Error> for Error {
 pub struct State {
     registry: Registry,
     pub metrics: Metrics,
+    pub usage_cursor: Arc<Mutex<UsageCursor>>,
+    pub usage_exporter: Option<Arc<UsageExporter>>,
+    pub namespaces: Store<Namespace>,
+    leader: Arc<AtomicBool>,
+    leader_notify: Arc<Notify>,
+    watcher_synced: Arc<AtomicBool>,
+    webhook_serving: Arc<AtomicBool>,
+    // Unset until the leader collects usage for the first time.
+    last_usage_collection: Arc<std::sync::Mutex<Option<DateTime<Utc>>>>,
 }
 impl State {
-    pub fn new() -> Self {
+    pub fn new(namespaces: Store<Namespace>) -> Self {
         let registry = Registry::default();
         let metrics = Metrics::default().register(&registry).unwrap();
-        Self { registry, metrics }
+        let usage_cursor = Arc::default();
+        Self {
+            registry,
+            metrics,
+            usage_cursor,
+            usage_exporter: UsageExporter::from_config().map(Arc::new),
+            namespaces,
+            leader: Arc::default(),
+            leader_notify: Arc::default(),
+            watcher_synced: Arc::default(),
+            webhook_serving: Arc::default(),
+            last_usage_collection: Arc::default(),
+        }
+    }
+
+    pub fn is_leader(&self) -> bool {
+        self.leader.load(Ordering::SeqCst)
+    }
+
+    pub fn set_leader(&self, leader: bool) {
+        self.leader.store(leader, Ordering::SeqCst);
+        self.leader_notify.notify_waiters();
+    }
+
+    pub fn set_watcher_synced(&self) {
+        self.watcher_synced.store(true, Ordering::SeqCst);
+    }
+
+    pub fn set_webhook_serving(&self) {
+        self.webhook_serving.store(true, Ordering::SeqCst);
+    }
+
+    pub fn mark_usage_collected(&self) {
+        *self.last_usage_collection.lock().unwrap() = Some(Utc::now());
+    }
+
+    pub fn reset_usage_collected(&self) {
+        *self.last_usage_collection.lock().unwrap() = None;
+    }
+
+    /// Standby replicas only serve metrics and webhooks, so they're always ready. The leader is
+    /// ready once its watcher synced and usage was collected within the last intervals, so it
+    /// isn't ready before its first collection succeeds.
+    pub fn is_ready(&self) -> bool {
+        if !self.is_leader() {
+            return true;
+        }
+
+        let config = get_config();
+        let Some(last_usage_collection) = *self.last_usage_collection.lock().unwrap() else {
+            return false;
+        };
+        let max_delay = config.metrics_delay * config.readiness_usage_intervals;
+
+        let usage_collected = match (Utc::now() - last_usage_collection).to_std() {
+            Ok(delay) => delay <= max_delay,
+            // The last collection is in the future when the clock moved back.
+            Err(_) => true,
+        };
+
+        self.watcher_synced.load(Ordering::SeqCst) && usage_collected
+    }
+
+    /// Whether the admission webhook is listening, apart from the readiness of the leader.
+    pub fn is_webhook_ready(&self) -> bool {
+        self.webhook_serving.load(Ordering::SeqCst)
+    }
+
+    pub async fn wait_for_leadership(&self) {
+        loop {
+            let notified = self.leader_notify.notified();
+            if self.is_leader() {
+                return;
+            }
+            notified.await;
+        }
     }
 
     pub fn metrics_collected(&self) -> Vec<prometheus::proto::MetricFamily> {
         self.registry.gather()
     }
 }
-impl Default for State {
-    fn default() -> Self {
-        Self::new()
-    }
-}
 
 pub use k8s_openapi;
 pub use kube;
 
 pub type Result<T, E = Error> = std::result::Result<T, E>;
 
+pub mod auth;
+pub use crate::auth::*;
+
+mod conditions;
+pub use conditions::*;
+
 pub mod controller;
 pub use crate::controller::*;
 
+pub mod leader;
+
 pub mod metrics;
 pub use metrics::*;
 
 mod config;
 pub use config::*;
 
+pub mod projects;
+pub use projects::*;
+
+mod quota;
+pub use quota::*;
+
+mod tiers;
+pub use tiers::*;
+
+mod usage;
+pub use usage::*;
+
 mod utils;
 pub use utils::*;
+
+mod validation;
+pub use validation::*;
+
+pub mod v1alpha1;
+
+pub mod webhook;
+
+#[cfg(test)]
+mod fixtures;
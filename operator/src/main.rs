@@ -2,7 +2,10 @@ use dotenv::dotenv;
 use std::{io, sync::Arc};
 use tracing::Level;
 
-use operator::{controller, metrics as metrics_collector, State};
+use operator::{
+    controller, kube::runtime::reflector, leader, metrics as metrics_collector, projects, webhook,
+    State,
+};
 
 #[tokio::main]
 async fn main() -> io::Result<()> {
@@ -13,11 +16,17 @@ async fn main() -> io::Result<()> {
 
     tracing_subscriber::fmt().with_max_level(Level::INFO).init();
 
-    let state = Arc::new(State::default());
+    let (namespaces, namespace_writer) = reflector::store();
+    let state = Arc::new(State::new(namespaces));
 
+    projects::run_namespace_reflector(namespace_writer);
+    leader::run_leader_election(state.clone());
     metrics_collector::run_metrics_collector(state.clone());
     metrics_collector::run_metrics_server(state.clone());
+    webhook::run_webhook_server(state.clone());
 
+    // Standby replicas keep serving metrics and webhooks until they take over.
+    state.wait_for_leadership().await;
     controller::run(state.clone()).await;
 
     Ok(())
@@ -1,29 +1,76 @@
-use chrono::Utc;
+use chrono::{DateTime, Duration as ChronoDuration, Utc};
 use http_body_util::{combinators::BoxBody, BodyExt, Full};
-use hyper::{body::Bytes, server::conn::http1, service::service_fn, Response};
+use hyper::{
+    body::{Bytes, Incoming},
+    server::conn::http1,
+    service::service_fn,
+    Method, Request, Response, StatusCode,
+};
 use hyper_util::rt::TokioIo;
-use kube::{Resource, ResourceExt};
-use prometheus::{opts, Encoder, IntCounterVec, Registry, TextEncoder};
-use regex::Regex;
-use serde::{Deserialize, Deserializer};
-use std::{net::SocketAddr, str::FromStr, sync::Arc};
+use k8s_openapi::{api::core::v1::ConfigMap, apimachinery::pkg::apis::meta::v1::ObjectMeta};
+use kube::{
+    api::{ListParams, Patch, PatchParams},
+    Api, Client, CustomResourceExt, Resource, ResourceExt,
+};
+use lazy_static::lazy_static;
+use prometheus::{opts, Encoder, IntCounterVec, IntGauge, Registry, TextEncoder};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    net::SocketAddr,
+    str::FromStr,
+    sync::Arc,
+};
 use tokio::net::TcpListener;
-use tracing::{error, info, instrument};
+use tracing::{error, info, instrument, warn};
 
-use crate::{get_config, Error, State, TrpPort};
+use crate::{
+    get_config, parse_consumer, patch_resource_status, resolve_project, Error, State, TrpPort,
+    TrpPortUsage, UsageRecord,
+};
 
 #[derive(Clone)]
 pub struct Metrics {
     pub usage: IntCounterVec,
     pub reconcile_failures: IntCounterVec,
     pub metrics_failures: IntCounterVec,
+    pub unknown_consumers: IntCounterVec,
+    pub usage_last_collection: IntGauge,
+    // Usage series with the time they stopped matching a port, if they did.
+    usage_series: Arc<std::sync::Mutex<HashMap<UsageSeries, Option<DateTime<Utc>>>>>,
+}
+
+/// Why a usage result couldn't be attributed to a port.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UnknownConsumerReason {
+    /// The consumer isn't a `namespace.name` pair.
+    Malformed,
+    /// The namespace of the consumer doesn't belong to a project.
+    UnknownProject,
+}
+impl UnknownConsumerReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            UnknownConsumerReason::Malformed => "malformed",
+            UnknownConsumerReason::UnknownProject => "unknown_project",
+        }
+    }
+}
+
+/// Labels of a `usage` series, besides the feature.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct UsageSeries {
+    pub project: String,
+    pub resource_name: String,
+    pub tier: String,
+    pub network: String,
 }
 
 impl Default for Metrics {
     fn default() -> Self {
         let usage = IntCounterVec::new(
             opts!("usage", "Feature usage",),
-            &["feature", "project", "resource_name", "tier"],
+            &["feature", "project", "resource_name", "tier", "network"],
         )
         .unwrap();
 
@@ -45,10 +92,28 @@ impl Default for Metrics {
         )
         .unwrap();
 
+        let unknown_consumers = IntCounterVec::new(
+            opts!(
+                "trp_operator_unknown_consumers_total",
+                "usage results of consumers without a known project",
+            ),
+            &["reason"],
+        )
+        .unwrap();
+
+        let usage_last_collection = IntGauge::new(
+            "trp_operator_usage_last_collection_timestamp_seconds",
+            "time of the last usage collection of the leader, zero when it isn't collecting",
+        )
+        .unwrap();
+
         Metrics {
             usage,
             reconcile_failures,
             metrics_failures,
+            unknown_consumers,
+            usage_last_collection,
+            usage_series: Arc::default(),
         }
     }
 }
@@ -57,31 +122,166 @@ impl Metrics {
     pub fn register(self, registry: &Registry) -> Result<Self, prometheus::Error> {
         registry.register(Box::new(self.reconcile_failures.clone()))?;
         registry.register(Box::new(self.metrics_failures.clone()))?;
+        registry.register(Box::new(self.unknown_consumers.clone()))?;
         registry.register(Box::new(self.usage.clone()))?;
+        registry.register(Box::new(self.usage_last_collection.clone()))?;
 
         Ok(self)
     }
 
     pub fn reconcile_failure(&self, crd: &TrpPort, e: &Error) {
         self.reconcile_failures
-            .with_label_values(&[crd.name_any().as_ref(), e.metric_label().as_ref()])
+            .with_label_values(&[crd.name_any().as_ref(), e.metric_label()])
             .inc()
     }
 
     pub fn metrics_failure(&self, e: &Error) {
         self.metrics_failures
-            .with_label_values(&[e.metric_label().as_ref()])
+            .with_label_values(&[e.metric_label()])
             .inc()
     }
 
-    pub fn count_usage(&self, project: &str, resource_name: &str, tier: &str, value: f64) {
+    /// Labelled by a fixed reason, the consumer itself only goes to logs.
+    pub fn unknown_consumer(&self, reason: UnknownConsumerReason) {
+        self.unknown_consumers
+            .with_label_values(&[reason.as_str()])
+            .inc()
+    }
+
+    /// Reports how fresh the usage is, so a stalled collector can be alerted on.
+    pub fn usage_collected(&self, at: DateTime<Utc>) {
+        self.usage_last_collection.set(at.timestamp())
+    }
+
+    pub fn usage_collection_stopped(&self) {
+        self.usage_last_collection.set(0)
+    }
+
+    pub fn count_usage(
+        &self,
+        project: &str,
+        resource_name: &str,
+        tier: &str,
+        network: &str,
+        value: u64,
+    ) {
         let feature = &TrpPort::kind(&());
-        let value: u64 = value.ceil() as u64;
 
         self.usage
-            .with_label_values(&[feature, project, resource_name, tier])
+            .with_label_values(&[feature, project, resource_name, tier, network])
             .inc_by(value);
+
+        let series = UsageSeries {
+            project: project.to_string(),
+            resource_name: resource_name.to_string(),
+            tier: tier.to_string(),
+            network: network.to_string(),
+        };
+        self.usage_series.lock().unwrap().insert(series, None);
+    }
+
+    /// Removes the usage series that don't belong to a live port anymore. A series is kept for
+    /// the grace period after it went stale, so scrapes still see its last value.
+    pub fn remove_stale_usage(&self, live: &HashSet<UsageSeries>, now: DateTime<Utc>) {
+        let feature = &TrpPort::kind(&());
+        let grace_period =
+            ChronoDuration::from_std(get_config().usage_series_grace_period).unwrap();
+
+        self.usage_series
+            .lock()
+            .unwrap()
+            .retain(|series, stale_since| {
+                if live.contains(series) {
+                    *stale_since = None;
+                    return true;
+                }
+
+                let stale_since = *stale_since.get_or_insert(now);
+                if now - stale_since < grace_period {
+                    return true;
+                }
+
+                let _ = self.usage.remove_label_values(&[
+                    feature,
+                    &series.project,
+                    &series.resource_name,
+                    &series.tier,
+                    &series.network,
+                ]);
+                false
+            });
+    }
+}
+
+static LAST_COLLECTION_KEY: &str = "lastCollection";
+static STATUS_USAGE_KEY: &str = "statusUsage";
+
+lazy_static! {
+    static ref PROMETHEUS_CLIENT: reqwest::Client = reqwest::Client::builder().build().unwrap();
+}
+
+/// Collection progress shared by the periodic collector and the port finalizer, so a window is
+/// never counted twice for the same consumer.
+pub struct UsageCursor {
+    last_collection: DateTime<Utc>,
+    // Whether `last_collection` was restored from the persisted state since becoming leader.
+    restored: bool,
+    // Consumers flushed before their port was deleted, with the time of the flush.
+    flushed: HashMap<String, DateTime<Utc>>,
+    // Usage not yet added to the status of the ports, by namespace and port name.
+    status_usage: HashMap<(String, String), Vec<StatusUsage>>,
+    last_status_update: DateTime<Utc>,
+}
+impl Default for UsageCursor {
+    fn default() -> Self {
+        Self {
+            last_collection: Utc::now(),
+            restored: false,
+            flushed: HashMap::new(),
+            status_usage: HashMap::new(),
+            last_status_update: Utc::now(),
+        }
+    }
+}
+impl UsageCursor {
+    fn add_status_usage(&mut self, record: UsageRecord) {
+        let pending = self
+            .status_usage
+            .entry((record.namespace, record.resource_name))
+            .or_default();
+
+        match pending.last_mut() {
+            Some(last) if last.window_start.date_naive() == record.window_start.date_naive() => {
+                last.units += record.units;
+                last.window_start = record.window_start;
+                last.window_end = record.window_end;
+            }
+            _ => pending.push(StatusUsage {
+                units: record.units,
+                window_start: record.window_start,
+                window_end: record.window_end,
+            }),
+        }
     }
+
+    /// Puts back usage that couldn't be added to the status, before the usage collected since.
+    fn restore_status_usage(&mut self, unapplied: Vec<((String, String), Vec<StatusUsage>)>) {
+        for (key, mut usage) in unapplied {
+            let pending = self.status_usage.entry(key).or_default();
+            usage.append(pending);
+            *pending = usage;
+        }
+    }
+}
+
+/// Units of a port waiting to be added to its status, merged by the UTC day their windows start
+/// in. The window bounds are the ones of the last merged window.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct StatusUsage {
+    units: u64,
+    window_start: DateTime<Utc>,
+    window_end: DateTime<Utc>,
 }
 
 #[instrument("metrics collector run", skip_all)]
@@ -90,73 +290,500 @@ pub fn run_metrics_collector(state: Arc<State>) {
         info!("collecting metrics running");
 
         let config = get_config();
-        let client = reqwest::Client::builder().build().unwrap();
-        let project_regex = Regex::new(r"prj-(.+)\.(.+)").unwrap();
-        let mut last_execution = Utc::now();
+        let client = Client::try_default()
+            .await
+            .expect("failed to create kube client");
+
+        // Consumers can't be resolved to projects before the namespaces are known.
+        if state.namespaces.wait_until_ready().await.is_err() {
+            error!("namespace reflector stopped");
+            std::process::exit(1);
+        }
 
         loop {
             tokio::time::sleep(config.metrics_delay).await;
 
-            let end = Utc::now();
-            let start = (end - last_execution).num_seconds();
+            // The finalizer flushes usage under the same lock, so it's released before the status
+            // of the ports is patched.
+            let pending = {
+                let mut cursor = state.usage_cursor.lock().await;
 
-            last_execution = end;
+                // Usage is counted by the leader, a standby restores the progress when elected.
+                if !state.is_leader() {
+                    cursor.last_collection = Utc::now();
+                    cursor.restored = false;
+                    state.reset_usage_collected();
+                    state.metrics.usage_collection_stopped();
+                    continue;
+                }
 
-            let query = format!(
-                "sum by (consumer, network, tier) (increase(trp_proxy_http_total_request{{status_code!~\"401|429|503\"}}[{start}s] @ {}))",
-                end.timestamp_millis() / 1000
-            );
+                if let Err(err) = restore_usage_progress(&client, &mut cursor).await {
+                    error!(error = err.to_string(), "error to restore usage progress");
+                    state.metrics.metrics_failure(&err);
+                    continue;
+                }
 
-            let result = client
-                .get(format!("{}/query?query={query}", config.prometheus_url))
-                .send()
-                .await;
+                match collect_usage(&state, &client, &mut cursor, Utc::now()).await {
+                    Ok(()) => {
+                        state.mark_usage_collected();
+                        state.metrics.usage_collected(Utc::now());
+                    }
+                    Err(err) => {
+                        error!(error = err.to_string(), "error to collect usage");
+                        state.metrics.metrics_failure(&err);
+                    }
+                }
 
-            if let Err(err) = result {
-                error!(error = err.to_string(), "error to make prometheus request");
-                state
-                    .metrics
-                    .metrics_failure(&Error::HttpError(err.to_string()));
-                continue;
+                let now = Utc::now();
+                let due = (now - cursor.last_status_update)
+                    .to_std()
+                    .unwrap_or_default()
+                    >= config.usage_status_interval;
+                due.then(|| {
+                    cursor.last_status_update = now;
+                    cursor.status_usage.drain().collect::<Vec<_>>()
+                })
+            };
+
+            if let Some(pending) = pending {
+                let (unapplied, result) = update_status_usage(&client, pending).await;
+
+                let mut cursor = state.usage_cursor.lock().await;
+                cursor.restore_status_usage(unapplied);
+                // The applied usage is dropped from the persisted progress.
+                let result = match result {
+                    Ok(()) => persist_usage_progress(&client, &cursor).await,
+                    Err(err) => Err(err),
+                };
+                if let Err(err) = result {
+                    error!(error = err.to_string(), "error to update usage status");
+                    state.metrics.metrics_failure(&err);
+                }
             }
 
-            let response = result.unwrap();
-            let status = response.status();
-            if status.is_client_error() || status.is_server_error() {
-                error!(status = status.to_string(), "request status code fail");
-                state.metrics.metrics_failure(&Error::HttpError(format!(
-                    "Prometheus request error. Status: {} Query: {}",
-                    status, query
-                )));
-                continue;
+            if let Err(err) = remove_stale_usage(&state, &client).await {
+                error!(error = err.to_string(), "error to remove stale usage");
+                state.metrics.metrics_failure(&err);
             }
+        }
+    });
+}
 
-            let response = response.json::<PrometheusResponse>().await.unwrap();
-            for result in response.data.result {
-                if result.value == 0.0
-                    || result.metric.consumer.is_none()
-                    || result.metric.network.is_none()
-                    || result.metric.tier.is_none()
-                {
-                    continue;
-                }
+/// Start of the usage window containing `time`. Windows are aligned to multiples of the metrics
+/// delay, so a window collected again produces records with the same ids.
+fn window_start(time: DateTime<Utc>) -> DateTime<Utc> {
+    let step = get_config().metrics_delay.as_secs().max(1) as i64;
+    let timestamp = time.timestamp();
+    DateTime::from_timestamp(timestamp - timestamp.rem_euclid(step), 0).unwrap()
+}
 
-                let consumer = result.metric.consumer.unwrap();
-                let project_captures = project_regex.captures(&consumer);
-                if project_captures.is_none() {
-                    continue;
-                }
-                let project_captures = project_captures.unwrap();
-                let project = project_captures.get(1).unwrap().as_str();
-                let resource_name = project_captures.get(2).unwrap().as_str();
-                let tier = result.metric.tier.unwrap();
+/// End of the usage window starting at `start`. A start that isn't aligned, like progress saved
+/// before the windows were aligned, ends at the next aligned boundary.
+fn end_of_window(start: DateTime<Utc>) -> DateTime<Utc> {
+    window_start(start) + ChronoDuration::from_std(get_config().metrics_delay).unwrap()
+}
+
+/// Counts usage from the last collection up to the last window ended before `end`. Long gaps,
+/// like the operator being down, are backfilled window by window and the progress is persisted
+/// every chunk. The cursor only moves past the windows that were counted, so a failed window is
+/// retried on the next run.
+async fn collect_usage(
+    state: &State,
+    client: &Client,
+    cursor: &mut UsageCursor,
+    end: DateTime<Utc>,
+) -> Result<(), Error> {
+    let config = get_config();
+    let max_backfill = ChronoDuration::from_std(config.metrics_max_backfill).unwrap();
+    let chunk = ChronoDuration::from_std(config.metrics_backfill_chunk).unwrap();
+    let end = window_start(end);
+
+    if end - cursor.last_collection > max_backfill {
+        warn!(
+            last_collection = cursor.last_collection.to_rfc3339(),
+            "usage older than the max backfill is skipped"
+        );
+        cursor.last_collection = window_start(end - max_backfill);
+    }
+
+    let mut persisted = cursor.last_collection;
+    while cursor.last_collection < end {
+        let start = cursor.last_collection;
+        let window_end = end_of_window(start);
+
+        let results = query_usage(start, window_end, None).await?;
+        let results = results.into_iter().filter(|result| {
+            !result
+                .metric
+                .consumer
+                .as_ref()
+                .is_some_and(|consumer| cursor.flushed.contains_key(consumer))
+        });
+        let records = usage_records(state, results, start, window_end);
+        export_usage(state, records.clone()).await?;
+
+        for record in records {
+            cursor.add_status_usage(record);
+        }
+
+        cursor.last_collection = window_end;
+        // Flushed consumers have no usage left after this window.
+        cursor
+            .flushed
+            .retain(|_, flushed_at| *flushed_at > window_end);
+
+        // Windows collected again after a restart are deduplicated by their record ids.
+        if window_end - persisted >= chunk || window_end >= end {
+            persist_usage_progress(client, cursor).await?;
+            persisted = window_end;
+        }
+    }
+
+    Ok(())
+}
+
+/// Adds the pending usage to the status of the ports, one patch per port spaced out by the
+/// configured delay. Returns the usage of the ports that couldn't be patched, to be kept for the
+/// next update.
+async fn update_status_usage(
+    client: &Client,
+    pending: Vec<((String, String), Vec<StatusUsage>)>,
+) -> (Vec<((String, String), Vec<StatusUsage>)>, Result<(), Error>) {
+    let config = get_config();
+
+    let mut pending = pending.into_iter();
+    while let Some(((namespace, name), usage)) = pending.next() {
+        if let Err(err) = patch_status_usage(client, &namespace, &name, &usage).await {
+            let mut unapplied = vec![((namespace, name), usage)];
+            unapplied.extend(pending);
+            return (unapplied, Err(err));
+        }
+
+        tokio::time::sleep(config.usage_status_patch_delay).await;
+    }
+
+    (Vec::new(), Ok(()))
+}
+
+/// Usage is charged to the day its windows start in. Windows that already ended before the last update
+/// of the status were applied before a restart and are skipped. Only the usage is patched, the
+/// status change triggers a reconcile, which updates the quota condition along with the others.
+async fn patch_status_usage(
+    client: &Client,
+    namespace: &str,
+    name: &str,
+    pending: &[StatusUsage],
+) -> Result<(), Error> {
+    let api: Api<TrpPort> = Api::namespaced(client.clone(), namespace);
+    // The usage of deleted ports is only exported.
+    let Some(port) = api.get_opt(name).await? else {
+        return Ok(());
+    };
+
+    let previous = port
+        .status
+        .as_ref()
+        .and_then(|status| status.usage.as_ref());
+
+    let usage = pending
+        .iter()
+        .filter(|entry| previous.is_none_or(|previous| entry.window_end > previous.last_updated))
+        .fold(None, |usage: Option<TrpPortUsage>, entry| {
+            Some(TrpPortUsage::add(
+                usage.as_ref().or(previous),
+                entry.units,
+                entry.window_start,
+                entry.window_end,
+            ))
+        });
+    let Some(usage) = usage else {
+        return Ok(());
+    };
+
+    patch_resource_status(
+        client.clone(),
+        namespace,
+        TrpPort::api_resource(),
+        name,
+        serde_json::json!({ "usage": usage }),
+    )
+    .await?;
+
+    Ok(())
+}
 
+/// Compares the usage series against the existing ports, so deleted and re-tiered ports stop
+/// being exported.
+async fn remove_stale_usage(state: &State, client: &Client) -> Result<(), Error> {
+    let ports = Api::<TrpPort>::all(client.clone())
+        .list(&ListParams::default())
+        .await?;
+
+    let live = ports
+        .items
+        .iter()
+        .filter_map(|port| {
+            let project = resolve_project(&state.namespaces, &port.namespace()?)?;
+            Some(UsageSeries {
+                project,
+                resource_name: port.name_any(),
+                tier: port.spec.throughput_tier.clone(),
+                network: port.spec.network.clone(),
+            })
+        })
+        .collect();
+
+    state.metrics.remove_stale_usage(&live, Utc::now());
+
+    Ok(())
+}
+
+/// Restores the collection progress and the usage still pending for the port status.
+/// Restores the persisted progress once per leadership, before the cursor is first used.
+async fn restore_usage_progress(client: &Client, cursor: &mut UsageCursor) -> Result<(), Error> {
+    if !cursor.restored {
+        load_usage_progress(client, cursor).await?;
+        cursor.restored = true;
+    }
+
+    Ok(())
+}
+
+async fn load_usage_progress(client: &Client, cursor: &mut UsageCursor) -> Result<(), Error> {
+    let config = get_config();
+    let Some(namespace) = &config.namespace else {
+        return Ok(());
+    };
+
+    let api: Api<ConfigMap> = Api::namespaced(client.clone(), namespace);
+    let Some(config_map) = api.get_opt(&config.usage_state_config_map).await? else {
+        return Ok(());
+    };
+    let data = config_map.data.unwrap_or_default();
+
+    let last_collection = data
+        .get(LAST_COLLECTION_KEY)
+        .map(|value| DateTime::parse_from_rfc3339(value))
+        .transpose()
+        .map_err(|err| Error::ConfigError(format!("invalid usage progress: {err}")))?
+        .map(|value| value.with_timezone(&Utc));
+
+    let status_usage = data
+        .get(STATUS_USAGE_KEY)
+        .map(|value| serde_json::from_str::<BTreeMap<String, Vec<StatusUsage>>>(value))
+        .transpose()?
+        .unwrap_or_default();
+
+    if let Some(last_collection) = last_collection {
+        info!(
+            last_collection = last_collection.to_rfc3339(),
+            "restored usage collection progress"
+        );
+        cursor.last_collection = last_collection;
+    }
+
+    // Namespace and port names can't contain slashes.
+    cursor.status_usage = status_usage
+        .into_iter()
+        .filter_map(|(key, usage)| {
+            let (namespace, name) = key.split_once('/')?;
+            Some(((namespace.to_string(), name.to_string()), usage))
+        })
+        .collect();
+
+    Ok(())
+}
+
+/// Stores the collection progress in a ConfigMap, so restarts and new leaders backfill from it.
+/// The usage pending for the port status is stored with it, so it's added after a restart.
+async fn persist_usage_progress(client: &Client, cursor: &UsageCursor) -> Result<(), Error> {
+    let config = get_config();
+    let Some(namespace) = &config.namespace else {
+        return Ok(());
+    };
+
+    let status_usage = cursor
+        .status_usage
+        .iter()
+        .map(|((namespace, name), usage)| (format!("{namespace}/{name}"), usage))
+        .collect::<BTreeMap<_, _>>();
+
+    let config_map = ConfigMap {
+        metadata: ObjectMeta {
+            name: Some(config.usage_state_config_map.clone()),
+            namespace: Some(namespace.clone()),
+            ..Default::default()
+        },
+        data: Some(BTreeMap::from([
+            (
+                LAST_COLLECTION_KEY.to_string(),
+                cursor.last_collection.to_rfc3339(),
+            ),
+            (
+                STATUS_USAGE_KEY.to_string(),
+                serde_json::to_string(&status_usage)?,
+            ),
+        ])),
+        ..Default::default()
+    };
+
+    let api: Api<ConfigMap> = Api::namespaced(client.clone(), namespace);
+    let patch_params = PatchParams::apply("trp-operator").force();
+    api.patch(
+        &config.usage_state_config_map,
+        &patch_params,
+        &Patch::Apply(&config_map),
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Counts the usage of a consumer since the last collection, up to the end of the current window.
+/// Called before its port is deleted, the following collections skip the consumer until that
+/// window so it isn't counted twice. The finalizer can run before the first collection, so the
+/// progress is restored first, otherwise the usage since the persisted progress would be lost.
+pub async fn flush_consumer_usage(
+    state: &State,
+    client: &Client,
+    consumer: &str,
+) -> Result<(), Error> {
+    let mut cursor = state.usage_cursor.lock().await;
+    restore_usage_progress(client, &mut cursor).await?;
+    let now = Utc::now();
+    let max_backfill = ChronoDuration::from_std(get_config().metrics_max_backfill).unwrap();
+
+    // Like a collection, usage older than the max backfill is skipped.
+    let mut start = cursor.last_collection.max(window_start(now - max_backfill));
+    while start < now {
+        let end = end_of_window(start);
+        // The records keep the whole window, so they match the ids of a regular collection.
+        let results = query_usage(start, end.min(now), Some(consumer)).await?;
+        export_usage(state, usage_records(state, results, start, end)).await?;
+        start = end;
+    }
+
+    cursor.flushed.insert(consumer.to_string(), start);
+
+    Ok(())
+}
+
+async fn query_usage(
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    consumer: Option<&str>,
+) -> Result<Vec<PrometheusDataResult>, Error> {
+    let config = get_config();
+
+    let window = (end - start).num_seconds();
+    if window <= 0 {
+        return Ok(Vec::new());
+    }
+
+    let selector = consumer
+        .map(|consumer| format!(",consumer=\"{consumer}\""))
+        .unwrap_or_default();
+
+    let query = config
+        .usage_query
+        .replace("$STATUS_CODES", &config.usage_excluded_status_codes)
+        .replace("$SELECTOR", &selector)
+        .replace("$WINDOW", &format!("{window}s"))
+        .replace("$END", &(end.timestamp_millis() / 1000).to_string());
+
+    let response = PROMETHEUS_CLIENT
+        .get(format!("{}/query", config.prometheus_url))
+        // Encoded, since the configurable query and status codes can hold `+`, `&` or `#`.
+        .query(&[("query", &query)])
+        .send()
+        .await
+        .map_err(|err| Error::HttpError(err.to_string()))?;
+
+    let status = response.status();
+    if status.is_client_error() || status.is_server_error() {
+        return Err(Error::HttpError(format!(
+            "Prometheus request error. Status: {} Query: {}",
+            status, query
+        )));
+    }
+
+    let body = response
+        .bytes()
+        .await
+        .map_err(|err| Error::HttpError(err.to_string()))?;
+    let response: PrometheusResponse =
+        serde_json::from_slice(&body).map_err(Error::PrometheusDecodeError)?;
+
+    Ok(response.data.result)
+}
+
+fn usage_records(
+    state: &State,
+    results: impl IntoIterator<Item = PrometheusDataResult>,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+) -> Vec<UsageRecord> {
+    let feature = TrpPort::kind(&());
+
+    results
+        .into_iter()
+        .filter(|result| result.value > 0.0)
+        .filter_map(|result| {
+            let consumer = result.metric.consumer?;
+            let network = result.metric.network?;
+            let tier = result.metric.tier?;
+
+            let Some((namespace, resource_name)) = parse_consumer(&consumer) else {
+                warn!(consumer = consumer.as_str(), "usage of malformed consumer");
                 state
                     .metrics
-                    .count_usage(project, resource_name, &tier, result.value);
-            }
-        }
-    });
+                    .unknown_consumer(UnknownConsumerReason::Malformed);
+                return None;
+            };
+            let Some(project) = resolve_project(&state.namespaces, namespace) else {
+                warn!(
+                    consumer = consumer.as_str(),
+                    "usage of consumer without project"
+                );
+                state
+                    .metrics
+                    .unknown_consumer(UnknownConsumerReason::UnknownProject);
+                return None;
+            };
+
+            Some(UsageRecord::new(
+                &feature,
+                &project,
+                namespace,
+                resource_name,
+                &tier,
+                &network,
+                start,
+                end,
+                result.value.ceil() as u64,
+            ))
+        })
+        .collect()
+}
+
+/// Sends the records to the usage sink, when configured, and counts them in the usage metric.
+/// Nothing is counted when the records couldn't be exported, so the window can be collected again.
+async fn export_usage(state: &State, records: Vec<UsageRecord>) -> Result<(), Error> {
+    if let Some(exporter) = &state.usage_exporter {
+        exporter.export(records.clone()).await?;
+    }
+
+    for record in records {
+        state.metrics.count_usage(
+            &record.project,
+            &record.resource_name,
+            &record.tier,
+            &record.network,
+            record.units,
+        );
+    }
+
+    Ok(())
 }
 
 pub fn run_metrics_server(state: Arc<State>) {
@@ -194,7 +821,7 @@ pub fn run_metrics_server(state: Arc<State>) {
             let io = TokioIo::new(stream);
 
             tokio::task::spawn(async move {
-                let service = service_fn(move |_| api_get_metrics(state.clone()));
+                let service = service_fn(move |req| api_router(req, state.clone()));
 
                 if let Err(err) = http1::Builder::new().serve_connection(io, service).await {
                     error!(error = err.to_string(), "failed metrics server connection");
@@ -204,6 +831,38 @@ pub fn run_metrics_server(state: Arc<State>) {
     });
 }
 
+async fn api_router(
+    req: Request<Incoming>,
+    state: Arc<State>,
+) -> Result<Response<BoxBody<Bytes, hyper::Error>>, hyper::Error> {
+    match (req.method(), req.uri().path()) {
+        (&Method::GET, "/metrics") => api_get_metrics(state).await,
+        (&Method::GET, "/healthz") => Ok(text_response(StatusCode::OK, "OK")),
+        (&Method::GET, "/readyz") if state.is_ready() => Ok(text_response(StatusCode::OK, "OK")),
+        (&Method::GET, "/readyz") => {
+            Ok(text_response(StatusCode::SERVICE_UNAVAILABLE, "NOT READY"))
+        }
+        (&Method::GET, "/readyz/webhook") if state.is_webhook_ready() => {
+            Ok(text_response(StatusCode::OK, "OK"))
+        }
+        (&Method::GET, "/readyz/webhook") => {
+            Ok(text_response(StatusCode::SERVICE_UNAVAILABLE, "NOT READY"))
+        }
+        _ => Ok(text_response(StatusCode::NOT_FOUND, "NOT FOUND")),
+    }
+}
+
+fn text_response(status: StatusCode, body: &'static str) -> Response<BoxBody<Bytes, hyper::Error>> {
+    Response::builder()
+        .status(status)
+        .body(
+            Full::new(Bytes::from(body))
+                .map_err(|never| match never {})
+                .boxed(),
+        )
+        .unwrap()
+}
+
 async fn api_get_metrics(
     state: Arc<State>,
 ) -> Result<Response<BoxBody<Bytes, hyper::Error>>, hyper::Error> {
@@ -252,10 +911,149 @@ fn deserialize_value<'de, D>(deserializer: D) -> Result<f64, D::Error>
 where
     D: Deserializer<'de>,
 {
-    let value: Vec<serde_json::Value> = Deserialize::deserialize(deserializer)?;
-    Ok(value.into_iter().as_slice()[1]
-        .as_str()
-        .unwrap()
-        .parse::<f64>()
-        .unwrap())
+    let (_timestamp, value): (serde_json::Value, String) = Deserialize::deserialize(deserializer)?;
+    value.parse::<f64>().map_err(serde::de::Error::custom)
+}
+
+#[cfg(test)]
+mod tests {
+    use prometheus::core::Collector;
+
+    use super::*;
+    use crate::fixtures::{at, init_config, record, NAMESPACE};
+
+    fn pending(cursor: &UsageCursor) -> Vec<(u64, DateTime<Utc>, DateTime<Utc>)> {
+        cursor.status_usage[&(NAMESPACE.to_string(), "port".to_string())]
+            .iter()
+            .map(|usage| (usage.units, usage.window_start, usage.window_end))
+            .collect()
+    }
+
+    fn exported_usage_series(metrics: &Metrics) -> usize {
+        metrics.usage.collect()[0].get_metric().len()
+    }
+
+    #[test]
+    fn removes_stale_usage_after_the_grace_period() {
+        init_config();
+        let grace_period =
+            ChronoDuration::from_std(get_config().usage_series_grace_period).unwrap();
+        let metrics = Metrics::default();
+        metrics.count_usage("project", "port", "0", "mainnet", 5);
+        let stale = HashSet::new();
+
+        metrics.remove_stale_usage(&stale, at(1, 31, 10));
+        metrics.remove_stale_usage(
+            &stale,
+            at(1, 31, 10) + grace_period - ChronoDuration::seconds(1),
+        );
+        assert_eq!(exported_usage_series(&metrics), 1);
+
+        metrics.remove_stale_usage(&stale, at(1, 31, 10) + grace_period);
+        assert_eq!(exported_usage_series(&metrics), 0);
+        assert!(metrics.usage_series.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn restarts_the_grace_period_of_live_usage() {
+        init_config();
+        let grace_period =
+            ChronoDuration::from_std(get_config().usage_series_grace_period).unwrap();
+        let metrics = Metrics::default();
+        metrics.count_usage("project", "port", "0", "mainnet", 5);
+        let live = HashSet::from([UsageSeries {
+            project: "project".into(),
+            resource_name: "port".into(),
+            tier: "0".into(),
+            network: "mainnet".into(),
+        }]);
+        let stale = HashSet::new();
+
+        metrics.remove_stale_usage(&stale, at(1, 31, 10));
+        metrics.remove_stale_usage(&live, at(1, 31, 10) + grace_period / 2);
+
+        // Stale again from here, the time it was stale before doesn't count.
+        let stale_since = at(1, 31, 10) + grace_period;
+        metrics.remove_stale_usage(&stale, stale_since);
+        metrics.remove_stale_usage(
+            &stale,
+            stale_since + grace_period - ChronoDuration::seconds(1),
+        );
+        assert_eq!(exported_usage_series(&metrics), 1);
+
+        metrics.remove_stale_usage(&stale, stale_since + grace_period);
+        assert_eq!(exported_usage_series(&metrics), 0);
+    }
+
+    #[test]
+    fn aligns_windows_to_the_metrics_delay() {
+        init_config();
+
+        assert_eq!(
+            window_start(at(1, 31, 10) + ChronoDuration::minutes(42)),
+            at(1, 31, 10)
+        );
+        assert_eq!(window_start(at(1, 31, 10)), at(1, 31, 10));
+        assert_eq!(end_of_window(at(1, 31, 10)), at(1, 31, 11));
+        assert_eq!(end_of_window(at(1, 31, 23)), at(2, 1, 0));
+    }
+
+    #[test]
+    fn ends_unaligned_windows_at_the_next_boundary() {
+        init_config();
+
+        // Progress restored from before the windows were aligned.
+        let start = at(1, 31, 10) + ChronoDuration::minutes(42);
+        let end = end_of_window(start);
+        assert_eq!(end, at(1, 31, 11));
+        assert_eq!(end_of_window(end), at(1, 31, 12));
+        assert_eq!(
+            end_of_window(at(1, 31, 23) + ChronoDuration::minutes(42)),
+            at(2, 1, 0)
+        );
+    }
+
+    #[test]
+    fn merges_status_usage_by_day() {
+        let mut cursor = UsageCursor::default();
+
+        cursor.add_status_usage(record("port", at(1, 31, 10), 5));
+        cursor.add_status_usage(record("port", at(1, 31, 23), 3));
+        cursor.add_status_usage(record("port", at(2, 1, 0), 2));
+
+        assert_eq!(
+            pending(&cursor),
+            [
+                (8, at(1, 31, 23), at(2, 1, 0)),
+                (2, at(2, 1, 0), at(2, 1, 1)),
+            ]
+        );
+    }
+
+    #[test]
+    fn restores_unapplied_status_usage_first() {
+        let mut cursor = UsageCursor::default();
+        cursor.add_status_usage(record("port", at(1, 31, 12), 2));
+
+        // Restored usage of the same day isn't merged, each entry is added to the status in turn.
+        let key = (NAMESPACE.to_string(), "port".to_string());
+        let unapplied = StatusUsage {
+            units: 5,
+            window_start: at(1, 31, 10) + ChronoDuration::minutes(42),
+            window_end: at(1, 31, 11),
+        };
+        cursor.restore_status_usage(vec![(key, vec![unapplied])]);
+
+        assert_eq!(
+            pending(&cursor),
+            [
+                (
+                    5,
+                    at(1, 31, 10) + ChronoDuration::minutes(42),
+                    at(1, 31, 11)
+                ),
+                (2, at(1, 31, 12), at(1, 31, 13)),
+            ]
+        );
+    }
 }
@@ -0,0 +1,137 @@
+use futures::StreamExt;
+use k8s_openapi::api::core::v1::Namespace;
+use kube::{
+    runtime::{
+        reflector::{self, store::Writer, ObjectRef, Store},
+        watcher, WatchStreamExt,
+    },
+    Api, Client, ResourceExt,
+};
+use tracing::{error, info, instrument};
+
+use crate::get_config;
+
+// Namespaces created before the project label existed are named after the project.
+static LEGACY_PROJECT_PREFIX: &str = "prj-";
+
+/// Splits a proxy consumer label into the port namespace and name. Namespace names can't contain
+/// dots, so everything after the first one is the port name.
+pub fn parse_consumer(consumer: &str) -> Option<(&str, &str)> {
+    consumer
+        .split_once('.')
+        .filter(|(namespace, port_name)| !namespace.is_empty() && !port_name.is_empty())
+}
+
+/// Project id of a namespace, from its project label or annotation. Namespaces without either
+/// fall back to the legacy `prj-` naming.
+pub fn resolve_project(namespaces: &Store<Namespace>, namespace: &str) -> Option<String> {
+    let config = get_config();
+    let object = namespaces.get(&ObjectRef::new(namespace))?;
+
+    object
+        .labels()
+        .get(&config.project_label)
+        .or_else(|| object.annotations().get(&config.project_label))
+        .cloned()
+        .or_else(|| {
+            namespace
+                .strip_prefix(LEGACY_PROJECT_PREFIX)
+                .map(|project| project.to_string())
+        })
+}
+
+/// Keeps the namespace cache used to resolve projects up to date.
+#[instrument("namespace reflector run", skip_all)]
+pub fn run_namespace_reflector(writer: Writer<Namespace>) {
+    tokio::spawn(async move {
+        info!("namespace reflector running");
+
+        let client = Client::try_default()
+            .await
+            .expect("failed to create kube client");
+        let api = Api::<Namespace>::all(client);
+
+        let stream = reflector::reflector(writer, watcher(api, watcher::Config::default()))
+            .default_backoff()
+            .touched_objects();
+        futures::pin_mut!(stream);
+
+        while let Some(result) = stream.next().await {
+            if let Err(err) = result {
+                error!(error = err.to_string(), "namespace watcher error");
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use kube::api::ObjectMeta;
+
+    use super::*;
+    use crate::fixtures::init_config;
+
+    fn namespace(name: &str, labels: &[(&str, &str)], annotations: &[(&str, &str)]) -> Namespace {
+        let entries = |entries: &[(&str, &str)]| {
+            Some(
+                entries
+                    .iter()
+                    .map(|(key, value)| (key.to_string(), value.to_string()))
+                    .collect::<BTreeMap<_, _>>(),
+            )
+        };
+
+        Namespace {
+            metadata: ObjectMeta {
+                name: Some(name.into()),
+                labels: entries(labels),
+                annotations: entries(annotations),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    fn store(namespaces: Vec<Namespace>) -> Store<Namespace> {
+        let (store, mut writer) = reflector::store();
+        for namespace in namespaces {
+            writer.apply_watcher_event(&watcher::Event::Apply(namespace));
+        }
+        store
+    }
+
+    #[test]
+    fn parses_consumers_with_dotted_port_names() {
+        assert_eq!(parse_consumer("prj-a.port"), Some(("prj-a", "port")));
+        assert_eq!(parse_consumer("prj-a.port.v2"), Some(("prj-a", "port.v2")));
+        assert_eq!(parse_consumer("prj-a."), None);
+        assert_eq!(parse_consumer(".port"), None);
+        assert_eq!(parse_consumer("prj-a"), None);
+    }
+
+    #[test]
+    fn resolves_projects_by_label_then_annotation_then_prefix() {
+        init_config();
+        let label = &get_config().project_label;
+        let namespaces = store(vec![
+            namespace("prj-a", &[(label, "labelled")], &[(label, "annotated")]),
+            namespace("prj-b", &[], &[(label, "annotated")]),
+            namespace("prj-c", &[], &[]),
+            namespace("team", &[], &[]),
+        ]);
+
+        assert_eq!(
+            resolve_project(&namespaces, "prj-a").as_deref(),
+            Some("labelled")
+        );
+        assert_eq!(
+            resolve_project(&namespaces, "prj-b").as_deref(),
+            Some("annotated")
+        );
+        assert_eq!(resolve_project(&namespaces, "prj-c").as_deref(), Some("c"));
+        assert_eq!(resolve_project(&namespaces, "team"), None);
+        assert_eq!(resolve_project(&namespaces, "prj-unknown"), None);
+    }
+}
@@ -0,0 +1,121 @@
+use chrono::{DateTime, Utc};
+
+use crate::{
+    get_config, load_tiers, ConditionStatus, TrpPortCondition, TrpPortSpec, TrpPortUsage,
+    CONDITION_QUOTA_EXCEEDED,
+};
+
+/// Monthly quota of a port, in units weighted by method cost. The spec overrides the quota of the tier.
+pub fn resolve_quota(spec: &TrpPortSpec) -> Option<u64> {
+    if spec.monthly_quota.is_some() {
+        return spec.monthly_quota;
+    }
+
+    let tiers = load_tiers(get_config().tiers_path.as_ref()?).ok()?;
+    tiers
+        .into_iter()
+        .find(|tier| tier.name == spec.throughput_tier)?
+        .monthly_quota
+}
+
+/// Whether the port used up its quota in the billing period of `now`. Usage of a previous
+/// billing period doesn't count, so the condition clears at the period boundary.
+pub fn quota_condition(
+    spec: &TrpPortSpec,
+    usage: Option<&TrpPortUsage>,
+    now: DateTime<Utc>,
+) -> TrpPortCondition {
+    let Some(quota) = resolve_quota(spec) else {
+        return TrpPortCondition::new(
+            CONDITION_QUOTA_EXCEEDED,
+            ConditionStatus::False,
+            "NoQuota",
+            "Port has no monthly quota".into(),
+        );
+    };
+
+    let units = usage
+        .filter(|usage| usage.billing_period_end() > now)
+        .map(|usage| usage.units_billing_period)
+        .unwrap_or_default();
+
+    if units >= quota {
+        return TrpPortCondition::new(
+            CONDITION_QUOTA_EXCEEDED,
+            ConditionStatus::True,
+            "QuotaExceeded",
+            format!("Used {units} of {quota} units in the billing period"),
+        );
+    }
+
+    TrpPortCondition::new(
+        CONDITION_QUOTA_EXCEEDED,
+        ConditionStatus::False,
+        "WithinQuota",
+        format!("Used {units} of {quota} units in the billing period"),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::fixtures::{at, init_config};
+
+    fn spec(throughput_tier: &str, monthly_quota: Option<u64>) -> TrpPortSpec {
+        serde_json::from_value(json!({
+            "network": "cardano-mainnet",
+            "throughputTier": throughput_tier,
+            "monthlyQuota": monthly_quota,
+        }))
+        .unwrap()
+    }
+
+    fn usage(units: u64) -> TrpPortUsage {
+        TrpPortUsage::add(None, units, at(1, 30, 10), at(1, 30, 11))
+    }
+
+    #[test]
+    fn spec_quota_overrides_the_tier() {
+        init_config();
+
+        assert_eq!(resolve_quota(&spec("0", None)), Some(100));
+        assert_eq!(resolve_quota(&spec("0", Some(10))), Some(10));
+        assert_eq!(resolve_quota(&spec("1", None)), None);
+
+        let condition = quota_condition(&spec("1", None), Some(&usage(500)), at(1, 31, 0));
+        assert!(condition.is(ConditionStatus::False));
+        assert_eq!(condition.reason, "NoQuota");
+    }
+
+    #[test]
+    fn exceeds_once_the_units_reach_the_quota() {
+        init_config();
+        let spec = spec("0", Some(10));
+
+        let condition = quota_condition(&spec, Some(&usage(9)), at(1, 31, 0));
+        assert!(condition.is(ConditionStatus::False));
+        assert_eq!(condition.reason, "WithinQuota");
+
+        let condition = quota_condition(&spec, Some(&usage(10)), at(1, 31, 0));
+        assert!(condition.is(ConditionStatus::True));
+        assert_eq!(condition.reason, "QuotaExceeded");
+    }
+
+    #[test]
+    fn ignores_usage_of_a_previous_billing_period() {
+        init_config();
+        let spec = spec("0", Some(10));
+
+        let condition = quota_condition(&spec, Some(&usage(50)), at(1, 31, 23));
+        assert!(condition.is(ConditionStatus::True));
+
+        let condition = quota_condition(&spec, Some(&usage(50)), at(2, 1, 0));
+        assert!(condition.is(ConditionStatus::False));
+        assert_eq!(
+            condition.message,
+            "Used 0 of 10 units in the billing period"
+        );
+    }
+}
@@ -0,0 +1,29 @@
+use serde::Deserialize;
+use std::{fs, path::Path};
+
+use crate::{Error, Result};
+
+/// Tier as defined in the proxy tiers file. The operator only needs to know which tiers exist
+/// and their quotas, the rates are enforced by the proxy.
+#[derive(Debug, Clone, Deserialize)]
+pub struct TierDefinition {
+    pub name: String,
+    #[serde(default)]
+    pub monthly_quota: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TiersFile {
+    #[serde(default)]
+    tiers: Vec<TierDefinition>,
+}
+
+pub fn load_tiers(path: &Path) -> Result<Vec<TierDefinition>> {
+    let contents = fs::read_to_string(path)
+        .map_err(|err| Error::ConfigError(format!("failed to read tiers file: {err}")))?;
+
+    let file: TiersFile = toml::from_str(&contents)
+        .map_err(|err| Error::ConfigError(format!("invalid tiers file: {err}")))?;
+
+    Ok(file.tiers)
+}
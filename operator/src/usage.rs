@@ -0,0 +1,358 @@
+use chrono::{DateTime, Utc};
+use futures::future::BoxFuture;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tracing::warn;
+
+use crate::{get_config, Error, Result, UsageSinkKind};
+
+/// Usage of a port in a collection window, in units weighted by the method costs of its tier. The
+/// id only depends on the port and the window, so a record delivered more than once can be
+/// deduplicated by the receiver.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UsageRecord {
+    pub id: String,
+    pub feature: String,
+    pub project: String,
+    pub namespace: String,
+    pub resource_name: String,
+    pub tier: String,
+    pub network: String,
+    pub window_start: DateTime<Utc>,
+    pub window_end: DateTime<Utc>,
+    pub units: u64,
+}
+impl UsageRecord {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        feature: &str,
+        project: &str,
+        namespace: &str,
+        resource_name: &str,
+        tier: &str,
+        network: &str,
+        window_start: DateTime<Utc>,
+        window_end: DateTime<Utc>,
+        units: u64,
+    ) -> Self {
+        let key = [
+            feature,
+            project,
+            resource_name,
+            tier,
+            network,
+            &window_start.to_rfc3339(),
+            &window_end.to_rfc3339(),
+        ]
+        .join("\n");
+
+        Self {
+            id: format!("{:x}", Sha256::digest(key.as_bytes())),
+            feature: feature.to_string(),
+            project: project.to_string(),
+            namespace: namespace.to_string(),
+            resource_name: resource_name.to_string(),
+            tier: tier.to_string(),
+            network: network.to_string(),
+            window_start,
+            window_end,
+            units,
+        }
+    }
+}
+
+/// Destination of the usage records.
+pub trait UsageSink: Send + Sync {
+    fn send<'a>(&'a self, records: &'a [UsageRecord]) -> BoxFuture<'a, Result<()>>;
+}
+
+/// Appends the records as JSON lines to a file.
+pub struct FileUsageSink {
+    path: PathBuf,
+}
+impl FileUsageSink {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+}
+impl UsageSink for FileUsageSink {
+    fn send<'a>(&'a self, records: &'a [UsageRecord]) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move { append_records(&self.path, records) })
+    }
+}
+
+const WEBHOOK_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Posts the records as a JSON array to an HTTP endpoint. Requests time out, since the usage
+/// cursor stays locked while they're sent.
+pub struct WebhookUsageSink {
+    url: String,
+    client: reqwest::Client,
+}
+impl WebhookUsageSink {
+    pub fn new(url: String, timeout: Duration) -> Self {
+        Self {
+            url,
+            client: reqwest::Client::builder()
+                .connect_timeout(WEBHOOK_CONNECT_TIMEOUT.min(timeout))
+                .timeout(timeout)
+                .build()
+                .unwrap(),
+        }
+    }
+}
+impl UsageSink for WebhookUsageSink {
+    fn send<'a>(&'a self, records: &'a [UsageRecord]) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let response = self
+                .client
+                .post(&self.url)
+                .json(records)
+                .send()
+                .await
+                .map_err(|err| Error::HttpError(err.to_string()))?;
+
+            let status = response.status();
+            if status.is_client_error() || status.is_server_error() {
+                return Err(Error::HttpError(format!(
+                    "Usage webhook request error. Status: {status}"
+                )));
+            }
+
+            Ok(())
+        })
+    }
+}
+
+/// Sends usage records to the configured sink. Records that still fail after the retries are kept
+/// in the spool file and sent again with the next export.
+pub struct UsageExporter {
+    sink: Box<dyn UsageSink>,
+    retries: u32,
+    spool_path: Option<PathBuf>,
+}
+impl UsageExporter {
+    pub fn new(sink: Box<dyn UsageSink>, retries: u32, spool_path: Option<PathBuf>) -> Self {
+        Self {
+            sink,
+            retries,
+            spool_path,
+        }
+    }
+
+    pub fn from_config() -> Option<Self> {
+        let config = get_config();
+
+        let sink: Box<dyn UsageSink> = match config.usage_sink.clone()? {
+            UsageSinkKind::File(path) => Box::new(FileUsageSink::new(path)),
+            UsageSinkKind::Webhook(url) => {
+                Box::new(WebhookUsageSink::new(url, config.usage_sink_timeout))
+            }
+        };
+
+        Some(Self::new(
+            sink,
+            config.usage_sink_retries,
+            config.usage_spool_path.clone(),
+        ))
+    }
+
+    /// Errors only when the records could neither be sent nor spooled, so the caller can collect
+    /// the window again.
+    pub async fn export(&self, records: Vec<UsageRecord>) -> Result<()> {
+        let mut pending = match &self.spool_path {
+            Some(path) => read_records(path)?,
+            None => Vec::new(),
+        };
+        pending.extend(records);
+
+        if pending.is_empty() {
+            return Ok(());
+        }
+
+        match self.send_with_retries(&pending).await {
+            Ok(()) => {
+                if let Some(path) = &self.spool_path {
+                    write_records(path, &[])?;
+                }
+                Ok(())
+            }
+            Err(err) => {
+                let Some(path) = &self.spool_path else {
+                    return Err(err);
+                };
+
+                warn!(
+                    error = err.to_string(),
+                    records = pending.len(),
+                    "failed to send usage, records spooled"
+                );
+                write_records(path, &pending)
+            }
+        }
+    }
+
+    async fn send_with_retries(&self, records: &[UsageRecord]) -> Result<()> {
+        let mut attempt = 0;
+        loop {
+            match self.sink.send(records).await {
+                Ok(()) => return Ok(()),
+                Err(err) if attempt >= self.retries => return Err(err),
+                Err(err) => {
+                    warn!(error = err.to_string(), attempt, "failed to send usage");
+                    tokio::time::sleep(Duration::from_millis(500 * 2u64.pow(attempt))).await;
+                    attempt += 1;
+                }
+            }
+        }
+    }
+}
+
+fn read_records(path: &Path) -> Result<Vec<UsageRecord>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(Error::SpoolError(err)),
+    };
+
+    let mut records = Vec::new();
+    for line in BufReader::new(file).lines() {
+        let line = line.map_err(Error::SpoolError)?;
+        if !line.trim().is_empty() {
+            records.push(serde_json::from_str(&line)?);
+        }
+    }
+
+    Ok(records)
+}
+
+/// Replaces the file contents through a rename, so a crash never leaves a partial spool.
+fn write_records(path: &Path, records: &[UsageRecord]) -> Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    let _ = fs::remove_file(&tmp_path);
+    append_records(&tmp_path, records)?;
+    fs::rename(&tmp_path, path).map_err(Error::SpoolError)
+}
+
+fn append_records(path: &Path, records: &[UsageRecord]) -> Result<()> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(Error::SpoolError)?;
+
+    for record in records {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        file.write_all(&line).map_err(Error::SpoolError)?;
+    }
+
+    file.sync_all().map_err(Error::SpoolError)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    };
+
+    use super::*;
+    use crate::fixtures::{at, record};
+
+    /// Keeps every batch it receives, or fails while `failing` is set.
+    #[derive(Default)]
+    struct FakeSink {
+        failing: Arc<AtomicBool>,
+        sent: Arc<Mutex<Vec<Vec<UsageRecord>>>>,
+    }
+    impl UsageSink for FakeSink {
+        fn send<'a>(&'a self, records: &'a [UsageRecord]) -> BoxFuture<'a, Result<()>> {
+            Box::pin(async move {
+                if self.failing.load(Ordering::SeqCst) {
+                    return Err(Error::HttpError("sink down".into()));
+                }
+                self.sent.lock().unwrap().push(records.to_vec());
+                Ok(())
+            })
+        }
+    }
+
+    fn spool_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("usage-{name}-{}.jsonl", std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn record_id_only_depends_on_port_and_window() {
+        assert_eq!(
+            record("port", at(1, 31, 10), 5).id,
+            record("port", at(1, 31, 10), 7).id
+        );
+        assert_ne!(
+            record("port", at(1, 31, 10), 5).id,
+            record("port", at(1, 31, 11), 5).id
+        );
+        assert_ne!(
+            record("port", at(1, 31, 10), 5).id,
+            record("other", at(1, 31, 10), 5).id
+        );
+    }
+
+    #[tokio::test]
+    async fn spools_failed_records_and_resends_them() {
+        let path = spool_path("resend");
+        let sink = FakeSink::default();
+        let failing = sink.failing.clone();
+        let sent = sink.sent.clone();
+        let exporter = UsageExporter::new(Box::new(sink), 0, Some(path.clone()));
+
+        failing.store(true, Ordering::SeqCst);
+        exporter
+            .export(vec![record("port", at(1, 31, 10), 5)])
+            .await
+            .unwrap();
+        assert_eq!(read_records(&path).unwrap().len(), 1);
+        assert!(sent.lock().unwrap().is_empty());
+
+        failing.store(false, Ordering::SeqCst);
+        exporter
+            .export(vec![record("port", at(1, 31, 11), 3)])
+            .await
+            .unwrap();
+        let ids = sent.lock().unwrap()[0]
+            .iter()
+            .map(|record| record.id.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            ids,
+            [
+                record("port", at(1, 31, 10), 5).id,
+                record("port", at(1, 31, 11), 3).id
+            ]
+        );
+        assert!(read_records(&path).unwrap().is_empty());
+
+        let _ = fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn fails_without_spool() {
+        let sink = FakeSink::default();
+        sink.failing.store(true, Ordering::SeqCst);
+        let exporter = UsageExporter::new(Box::new(sink), 0, None);
+
+        assert!(exporter
+            .export(vec![record("port", at(1, 31, 10), 5)])
+            .await
+            .is_err());
+    }
+}
@@ -30,3 +30,8 @@ pub fn build_hostname(network: &str) -> String {
     let dns_zone = &config.dns_zone;
     format!("{network}.{extension_subdomain}.{dns_zone}")
 }
+
+/// Name the proxy gives to the consumer of a port in its metrics.
+pub fn build_consumer_name(namespace: &str, port_name: &str) -> String {
+    format!("{namespace}.{port_name}")
+}
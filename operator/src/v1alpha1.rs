@@ -0,0 +1,244 @@
+use kube::{CustomResource, Resource};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{Error, Result, TrpPortAuthKey, TrpPortStatus};
+
+/// Holds the fields of the latest spec that v1alpha1 can't represent, so a v1alpha1 client that
+/// reads and writes back a port doesn't drop them.
+pub static LATEST_FIELDS_ANNOTATION: &str = "trpports.demeter.run/v1beta1-fields";
+
+#[derive(CustomResource, Deserialize, Serialize, Clone, Debug, JsonSchema)]
+#[kube(
+    kind = "TrpPort",
+    group = "demeter.run",
+    version = "v1alpha1",
+    shortname = "trpports",
+    category = "demeter-port",
+    namespaced
+)]
+#[kube(status = "TrpPortStatus")]
+#[kube(printcolumn = r#"
+        {"name": "Network", "jsonPath": ".spec.network", "type": "string"},
+        {"name": "Throughput Tier", "jsonPath":".spec.throughputTier", "type": "string"}, 
+        {"name": "Endpoint URL", "jsonPath": ".status.endpointUrl", "type": "string"},
+        {"name": "Auth Secret", "jsonPath": ".status.authTokenSecret", "type": "string"},
+        {"name": "Ready", "jsonPath": ".status.conditions[?(@.type==\"Ready\")].status", "type": "string"}
+    "#)]
+#[serde(rename_all = "camelCase")]
+/// First version of the port, still served for existing clients. Objects are stored as the
+/// latest version and converted by the conversion webhook.
+pub struct TrpPortSpec {
+    pub network: String,
+    pub throughput_tier: String,
+    /// Left empty to let the operator generate one.
+    #[serde(default)]
+    pub auth_token: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub auth_keys: Vec<TrpPortAuthKey>,
+}
+
+impl From<TrpPortSpec> for crate::TrpPortSpec {
+    fn from(value: TrpPortSpec) -> Self {
+        Self {
+            network: value.network,
+            throughput_tier: value.throughput_tier,
+            auth_token: Some(value.auth_token).filter(|token| !token.is_empty()),
+            auth_keys: value.auth_keys,
+            monthly_quota: None,
+            suspended: false,
+            suspend_reason: None,
+        }
+    }
+}
+
+impl From<crate::TrpPortSpec> for TrpPortSpec {
+    fn from(value: crate::TrpPortSpec) -> Self {
+        Self {
+            network: value.network,
+            throughput_tier: value.throughput_tier,
+            auth_token: value.auth_token.unwrap_or_default(),
+            auth_keys: value.auth_keys,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Default, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+struct LatestFields {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    monthly_quota: Option<u64>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    suspended: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    suspend_reason: Option<String>,
+}
+
+impl From<TrpPort> for crate::TrpPort {
+    fn from(value: TrpPort) -> Self {
+        let mut metadata = value.metadata;
+        let fields = metadata
+            .annotations
+            .as_mut()
+            .and_then(|annotations| annotations.remove(LATEST_FIELDS_ANNOTATION))
+            .and_then(|fields| serde_json::from_str::<LatestFields>(&fields).ok())
+            .unwrap_or_default();
+        if metadata
+            .annotations
+            .as_ref()
+            .is_some_and(|annotations| annotations.is_empty())
+        {
+            metadata.annotations = None;
+        }
+
+        let mut spec: crate::TrpPortSpec = value.spec.into();
+        spec.monthly_quota = fields.monthly_quota;
+        spec.suspended = fields.suspended;
+        spec.suspend_reason = fields.suspend_reason;
+
+        Self {
+            metadata,
+            spec,
+            status: value.status,
+        }
+    }
+}
+
+impl From<crate::TrpPort> for TrpPort {
+    fn from(value: crate::TrpPort) -> Self {
+        let mut metadata = value.metadata;
+        let fields = LatestFields {
+            monthly_quota: value.spec.monthly_quota,
+            suspended: value.spec.suspended,
+            suspend_reason: value.spec.suspend_reason.clone(),
+        };
+        if let Some(annotations) = metadata.annotations.as_mut() {
+            annotations.remove(LATEST_FIELDS_ANNOTATION);
+        }
+        if fields != LatestFields::default() {
+            metadata
+                .annotations
+                .get_or_insert_with(Default::default)
+                .insert(
+                    LATEST_FIELDS_ANNOTATION.to_string(),
+                    serde_json::to_string(&fields).unwrap(),
+                );
+        }
+
+        Self {
+            metadata,
+            spec: value.spec.into(),
+            status: value.status,
+        }
+    }
+}
+
+/// Converts a TrpPort object of any served version to `desired_api_version`.
+pub fn convert(object: Value, desired_api_version: &str) -> Result<Value> {
+    let api_version = object
+        .get("apiVersion")
+        .and_then(Value::as_str)
+        .unwrap_or_default();
+
+    if api_version == desired_api_version {
+        return Ok(object);
+    }
+
+    let alpha = TrpPort::api_version(&());
+    let beta = crate::TrpPort::api_version(&());
+
+    let converted = match (api_version, desired_api_version) {
+        (from, to) if from == alpha && to == beta => {
+            let port: TrpPort = serde_json::from_value(object)?;
+            serde_json::to_value(crate::TrpPort::from(port))?
+        }
+        (from, to) if from == beta && to == alpha => {
+            let port: crate::TrpPort = serde_json::from_value(object)?;
+            serde_json::to_value(TrpPort::from(port))?
+        }
+        (from, to) => {
+            return Err(Error::ConversionError(format!(
+                "unsupported conversion from {from} to {to}"
+            )))
+        }
+    };
+
+    Ok(converted)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    const ALPHA: &str = "demeter.run/v1alpha1";
+    const BETA: &str = "demeter.run/v1beta1";
+
+    fn port(api_version: &str, spec: Value) -> Value {
+        json!({
+            "apiVersion": api_version,
+            "kind": "TrpPort",
+            "metadata": { "name": "port", "namespace": "prj-test" },
+            "spec": spec,
+        })
+    }
+
+    #[test]
+    fn converts_alpha_to_beta_and_back() {
+        let alpha = port(
+            ALPHA,
+            json!({ "network": "cardano-mainnet", "throughputTier": "0", "authToken": "token" }),
+        );
+
+        let beta = convert(alpha.clone(), BETA).unwrap();
+        assert_eq!(beta["apiVersion"], BETA);
+        assert_eq!(beta["spec"]["authToken"], "token");
+        assert!(beta["spec"].get("suspended").is_none());
+
+        let back = convert(beta, ALPHA).unwrap();
+        assert_eq!(back["spec"], alpha["spec"]);
+        assert!(back["metadata"].get("annotations").is_none());
+    }
+
+    #[test]
+    fn keeps_beta_fields_through_alpha() {
+        let beta = port(
+            BETA,
+            json!({
+                "network": "cardano-mainnet",
+                "throughputTier": "0",
+                "authToken": "token",
+                "monthlyQuota": 1000,
+                "suspended": true,
+                "suspendReason": "unpaid",
+            }),
+        );
+
+        let alpha = convert(beta.clone(), ALPHA).unwrap();
+        assert!(alpha["spec"].get("suspended").is_none());
+        assert!(alpha["metadata"]["annotations"][LATEST_FIELDS_ANNOTATION].is_string());
+
+        let back = convert(alpha, BETA).unwrap();
+        assert_eq!(back["spec"], beta["spec"]);
+        assert!(back["metadata"].get("annotations").is_none());
+    }
+
+    #[test]
+    fn keeps_an_empty_alpha_token_unset() {
+        let alpha = port(
+            ALPHA,
+            json!({ "network": "cardano-mainnet", "throughputTier": "0", "authToken": "" }),
+        );
+
+        let beta = convert(alpha, BETA).unwrap();
+        assert!(beta["spec"]["authToken"].is_null());
+    }
+
+    #[test]
+    fn rejects_unknown_versions() {
+        let object = port("demeter.run/v2", json!({}));
+        assert!(convert(object, BETA).is_err());
+    }
+}
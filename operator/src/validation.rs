@@ -0,0 +1,115 @@
+use crate::{get_config, load_tiers, ConditionStatus, TrpPortSpec};
+
+/// Result of checking a field of a port spec. `Unknown` is used when the operator has nothing to
+/// check the field against.
+#[derive(Debug, Clone)]
+pub struct Validation {
+    pub status: ConditionStatus,
+    pub reason: &'static str,
+    pub message: String,
+}
+impl Validation {
+    fn new(status: ConditionStatus, reason: &'static str, message: String) -> Self {
+        Self {
+            status,
+            reason,
+            message,
+        }
+    }
+
+    pub fn is_invalid(&self) -> bool {
+        self.status == ConditionStatus::False
+    }
+}
+
+pub fn validate_network(network: &str) -> Validation {
+    let networks = &get_config().networks;
+
+    if networks.iter().any(|n| n == network) {
+        return Validation::new(
+            ConditionStatus::True,
+            "NetworkAvailable",
+            format!("Network {network} is available"),
+        );
+    }
+
+    Validation::new(
+        ConditionStatus::False,
+        "UnknownNetwork",
+        format!("Network {network} is not one of: {}", networks.join(", ")),
+    )
+}
+
+pub fn validate_tier(tier: &str) -> Validation {
+    let Some(tiers_path) = &get_config().tiers_path else {
+        return Validation::new(
+            ConditionStatus::Unknown,
+            "TiersNotConfigured",
+            "The operator has no tiers file to check the tier against".into(),
+        );
+    };
+
+    match load_tiers(tiers_path) {
+        Ok(tiers) if tiers.iter().any(|t| t.name == tier) => Validation::new(
+            ConditionStatus::True,
+            "TierFound",
+            format!("Tier {tier} is defined"),
+        ),
+        Ok(_) => Validation::new(
+            ConditionStatus::False,
+            "UnknownTier",
+            format!("Tier {tier} is missing from the tiers file"),
+        ),
+        Err(err) => Validation::new(
+            ConditionStatus::Unknown,
+            "TiersUnavailable",
+            err.to_string(),
+        ),
+    }
+}
+
+/// Checks the keys set on the spec. A missing auth token is valid, the operator generates one.
+/// The tokens of the rotation keys live in Secrets, they're checked when the operator reads them.
+pub fn validate_keys(spec: &TrpPortSpec) -> Validation {
+    let min_length = get_config().min_auth_token_length;
+
+    let too_short = spec
+        .auth_token
+        .iter()
+        .filter(|token| !token.is_empty())
+        .any(|token| token.chars().count() < min_length);
+    if too_short {
+        return Validation::new(
+            ConditionStatus::False,
+            "KeyTooShort",
+            format!("Auth keys must have at least {min_length} characters"),
+        );
+    }
+
+    if spec.auth_keys.iter().any(|key| key.secret_name.is_empty()) {
+        return Validation::new(
+            ConditionStatus::False,
+            "KeySecretMissing",
+            "Rotation keys must name the Secret holding them".into(),
+        );
+    }
+
+    Validation::new(
+        ConditionStatus::True,
+        "KeysValid",
+        "Auth keys are valid".into(),
+    )
+}
+
+/// Messages of every check the spec fails. Checks that can't be decided don't reject the spec.
+pub fn validate_spec(spec: &TrpPortSpec) -> Vec<String> {
+    [
+        validate_network(&spec.network),
+        validate_tier(&spec.throughput_tier),
+        validate_keys(spec),
+    ]
+    .into_iter()
+    .filter(Validation::is_invalid)
+    .map(|validation| validation.message)
+    .collect()
+}
@@ -0,0 +1,336 @@
+use http_body_util::{combinators::BoxBody, BodyExt, Full};
+use hyper::{
+    body::{Bytes, Incoming},
+    server::conn::http1,
+    service::service_fn,
+    Method, Request, Response, StatusCode,
+};
+use hyper_util::rt::TokioIo;
+use kube::{
+    core::admission::{AdmissionRequest, AdmissionResponse, AdmissionReview, Operation},
+    Resource,
+};
+use rustls::{
+    pki_types::{PrivateKeyDer, PrivatePkcs8KeyDer},
+    ServerConfig,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{fs::File, io::BufReader, net::SocketAddr, str::FromStr, sync::Arc};
+use tokio::net::TcpListener;
+use tokio_rustls::TlsAcceptor;
+use tracing::{error, info, warn};
+
+use crate::{get_config, v1alpha1, validate_spec, Error, State, TrpPort};
+
+pub fn run_webhook_server(state: Arc<State>) {
+    tokio::spawn(async move {
+        let config = get_config();
+
+        let tls_config = match load_tls_config() {
+            Ok(Some(tls_config)) => tls_config,
+            Ok(None) => {
+                warn!("webhook certificate not configured, admission webhook disabled");
+                // Nothing to wait for without a webhook.
+                state.set_webhook_serving();
+                return;
+            }
+            Err(err) => {
+                error!(error = err.to_string(), "invalid webhook certificate");
+                std::process::exit(1);
+            }
+        };
+        let acceptor = TlsAcceptor::from(Arc::new(tls_config));
+
+        let addr_result = SocketAddr::from_str(&config.webhook_addr);
+        if let Err(err) = addr_result {
+            error!(error = err.to_string(), "invalid webhook addr");
+            std::process::exit(1);
+        }
+        let addr = addr_result.unwrap();
+
+        let listener_result = TcpListener::bind(addr).await;
+        if let Err(err) = listener_result {
+            error!(
+                error = err.to_string(),
+                "fail to bind tcp webhook server listener"
+            );
+            std::process::exit(1);
+        }
+        let listener = listener_result.unwrap();
+
+        info!(addr = addr.to_string(), "webhook listening");
+        state.set_webhook_serving();
+
+        loop {
+            let accept_result = listener.accept().await;
+            if let Err(err) = accept_result {
+                error!(error = err.to_string(), "accept client webhook server");
+                continue;
+            }
+            let (stream, _) = accept_result.unwrap();
+
+            let acceptor = acceptor.clone();
+
+            tokio::task::spawn(async move {
+                let stream = match acceptor.accept(stream).await {
+                    Ok(stream) => stream,
+                    Err(err) => {
+                        error!(error = err.to_string(), "failed webhook tls handshake");
+                        return;
+                    }
+                };
+
+                let io = TokioIo::new(stream);
+                let service = service_fn(api_webhook);
+
+                if let Err(err) = http1::Builder::new().serve_connection(io, service).await {
+                    error!(error = err.to_string(), "failed webhook server connection");
+                }
+            });
+        }
+    });
+}
+
+/// Loads the certificate files, or generates a self-signed certificate for local testing.
+/// Returns `None` when neither is configured.
+fn load_tls_config() -> Result<Option<ServerConfig>, Error> {
+    let config = get_config();
+
+    let (certs, key) = match (&config.webhook_cert_path, &config.webhook_key_path) {
+        (Some(cert_path), Some(key_path)) => {
+            let mut cert_reader = BufReader::new(File::open(cert_path).map_err(tls_error)?);
+            let certs = rustls_pemfile::certs(&mut cert_reader)
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(tls_error)?;
+
+            let mut key_reader = BufReader::new(File::open(key_path).map_err(tls_error)?);
+            let key = rustls_pemfile::private_key(&mut key_reader)
+                .map_err(tls_error)?
+                .ok_or_else(|| Error::ConfigError("webhook key file has no key".into()))?;
+
+            (certs, key)
+        }
+        _ if config.webhook_self_signed => {
+            let certified =
+                rcgen::generate_simple_self_signed(vec!["localhost".into()]).map_err(tls_error)?;
+            info!(
+                cert = certified.cert.pem(),
+                "generated self-signed webhook certificate"
+            );
+
+            let key = PrivatePkcs8KeyDer::from(certified.key_pair.serialize_der());
+            (
+                vec![certified.cert.der().clone()],
+                PrivateKeyDer::Pkcs8(key),
+            )
+        }
+        _ => return Ok(None),
+    };
+
+    let mut tls_config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(tls_error)?;
+    tls_config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+    Ok(Some(tls_config))
+}
+
+fn tls_error(err: impl ToString) -> Error {
+    Error::ConfigError(format!("webhook tls: {}", err.to_string()))
+}
+
+async fn api_webhook(
+    req: Request<Incoming>,
+) -> Result<Response<BoxBody<Bytes, hyper::Error>>, hyper::Error> {
+    match (req.method(), req.uri().path()) {
+        (&Method::POST, "/validate") => api_validate(req).await,
+        (&Method::POST, "/convert") => api_convert(req).await,
+        _ => Ok(empty_response(StatusCode::NOT_FOUND)),
+    }
+}
+
+async fn api_validate(
+    req: Request<Incoming>,
+) -> Result<Response<BoxBody<Bytes, hyper::Error>>, hyper::Error> {
+    let body = req.into_body().collect().await?.to_bytes();
+
+    let review = match serde_json::from_slice(&body)
+        .map_err(Error::from)
+        .and_then(latest_review)
+    {
+        Ok(review) => review,
+        Err(err) => {
+            warn!(error = err.to_string(), "invalid admission review");
+            return Ok(empty_response(StatusCode::BAD_REQUEST));
+        }
+    };
+
+    let request: AdmissionRequest<TrpPort> = match review.try_into() {
+        Ok(request) => request,
+        Err(err) => {
+            warn!(error = err.to_string(), "invalid admission request");
+            return Ok(empty_response(StatusCode::BAD_REQUEST));
+        }
+    };
+
+    let mut response = AdmissionResponse::from(&request);
+
+    // Deletions carry no object to validate.
+    if let Some(crd) = request
+        .object
+        .as_ref()
+        .filter(|crd| spec_changed(&request, crd))
+    {
+        let errors = validate_spec(&crd.spec);
+        if !errors.is_empty() {
+            info!(resource = request.name.as_str(), "admission denied");
+            response = response.deny(Error::ValidationError(errors.join("; ")).to_string());
+        }
+    }
+
+    Ok(json_response(&response.into_review()))
+}
+
+/// Ports requested in v1alpha1 are validated as v1beta1, the version the controller reads.
+fn latest_review(mut review: Value) -> Result<AdmissionReview<TrpPort>, Error> {
+    if let Some(request) = review.get_mut("request") {
+        for key in ["object", "oldObject"] {
+            if let Some(object) = request.get_mut(key).filter(|object| !object.is_null()) {
+                *object = v1alpha1::convert(object.take(), &TrpPort::api_version(&()))?;
+            }
+        }
+    }
+
+    Ok(serde_json::from_value(review)?)
+}
+
+/// Only new or changed specs are validated. Ports that became invalid after a config change must
+/// still accept the finalizer and status updates of the operator, and ports being deleted are
+/// never blocked.
+fn spec_changed(request: &AdmissionRequest<TrpPort>, crd: &TrpPort) -> bool {
+    if crd.metadata.deletion_timestamp.is_some() {
+        return false;
+    }
+    if request.operation == Operation::Create {
+        return true;
+    }
+
+    match &request.old_object {
+        Some(old) => serde_json::to_value(&old.spec).ok() != serde_json::to_value(&crd.spec).ok(),
+        None => true,
+    }
+}
+
+async fn api_convert(
+    req: Request<Incoming>,
+) -> Result<Response<BoxBody<Bytes, hyper::Error>>, hyper::Error> {
+    let body = req.into_body().collect().await?.to_bytes();
+
+    let review = match serde_json::from_slice::<ConversionReview>(&body) {
+        Ok(review) => review,
+        Err(err) => {
+            warn!(error = err.to_string(), "invalid conversion review");
+            return Ok(empty_response(StatusCode::BAD_REQUEST));
+        }
+    };
+
+    let Some(request) = review.request else {
+        warn!("conversion review without request");
+        return Ok(empty_response(StatusCode::BAD_REQUEST));
+    };
+
+    let converted = request
+        .objects
+        .into_iter()
+        .map(|object| v1alpha1::convert(object, &request.desired_api_version))
+        .collect::<Result<Vec<_>, Error>>();
+
+    let response = match converted {
+        Ok(converted_objects) => ConversionResponse {
+            uid: request.uid,
+            converted_objects,
+            result: ConversionResult {
+                status: "Success".into(),
+                message: None,
+            },
+        },
+        Err(err) => {
+            error!(error = err.to_string(), "conversion failed");
+            ConversionResponse {
+                uid: request.uid,
+                converted_objects: Vec::new(),
+                result: ConversionResult {
+                    status: "Failure".into(),
+                    message: Some(err.to_string()),
+                },
+            }
+        }
+    };
+
+    Ok(json_response(&ConversionReview {
+        api_version: review.api_version,
+        kind: review.kind,
+        request: None,
+        response: Some(response),
+    }))
+}
+
+fn json_response(value: &impl Serialize) -> Response<BoxBody<Bytes, hyper::Error>> {
+    let body = serde_json::to_vec(value).unwrap();
+
+    Response::builder()
+        .header("content-type", "application/json")
+        .body(
+            Full::new(body.into())
+                .map_err(|never| match never {})
+                .boxed(),
+        )
+        .unwrap()
+}
+
+fn empty_response(status: StatusCode) -> Response<BoxBody<Bytes, hyper::Error>> {
+    Response::builder()
+        .status(status)
+        .body(
+            Full::new(Bytes::new())
+                .map_err(|never| match never {})
+                .boxed(),
+        )
+        .unwrap()
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ConversionReview {
+    api_version: String,
+    kind: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request: Option<ConversionRequest>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response: Option<ConversionResponse>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ConversionRequest {
+    uid: String,
+    desired_api_version: String,
+    objects: Vec<Value>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ConversionResponse {
+    uid: String,
+    converted_objects: Vec<Value>,
+    result: ConversionResult,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct ConversionResult {
+    status: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message: Option<String>,
+}
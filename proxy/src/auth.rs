@@ -1,14 +1,20 @@
 use std::sync::Arc;
 
 use async_trait::async_trait;
-use futures_util::TryStreamExt;
+use futures_util::{stream, StreamExt};
 
 use operator::{
+    hash_key,
+    k8s_openapi::{api::core::v1::Secret, ByteString},
     kube::{
-        runtime::watcher::{self, Config as ConfigWatcher, Event},
+        runtime::{
+            reflector::{self, ObjectRef, Store},
+            watcher::{self, Config as ConfigWatcher, Event},
+            WatchStreamExt,
+        },
         Api, Client, ResourceExt,
     },
-    TrpPort,
+    read_auth_token, TrpPort, TRP_PORT_LABEL,
 };
 use pingora::{server::ShutdownWatch, services::background::BackgroundService};
 use tokio::pin;
@@ -16,6 +22,8 @@ use tracing::{error, info};
 
 use crate::{Consumer, State};
 
+static LAST_APPLIED_ANNOTATION: &str = "kubectl.kubernetes.io/last-applied-configuration";
+
 pub struct AuthBackgroundService {
     state: Arc<State>,
 }
@@ -23,6 +31,77 @@ impl AuthBackgroundService {
     pub fn new(state: Arc<State>) -> Self {
         Self { state }
     }
+
+    /// Rebuilds every consumer from the listed ports and secrets and swaps them in at once.
+    async fn load_consumers(&self, ports: &Store<TrpPort>, secrets: &Store<Secret>) {
+        let consumers = ports
+            .state()
+            .iter()
+            .filter(|crd| crd.status.is_some())
+            .map(|crd| build_consumer(secrets, crd))
+            .collect::<Vec<_>>();
+
+        info!(
+            "auth: Watcher restart finished, loaded {} consumers",
+            consumers.len()
+        );
+        self.state.replace_consumers(consumers).await;
+    }
+}
+
+/// Replaces the token of a watched port with its hash before it's cached, so the stores never
+/// hold a live key. The last applied configuration can hold the token too.
+fn hash_port_tokens(crd: &mut TrpPort) {
+    crd.annotations_mut().remove(LAST_APPLIED_ANNOTATION);
+    crd.spec.auth_token = crd
+        .spec
+        .auth_token
+        .take()
+        .filter(|token| !token.is_empty())
+        .map(|token| hash_key(&token));
+}
+
+/// Replaces every value of a watched auth secret with its hash before it's cached, the auth
+/// token and the tokens of the rotation keys alike.
+fn hash_secret_tokens(secret: &mut Secret) {
+    secret.annotations_mut().remove(LAST_APPLIED_ANNOTATION);
+    for value in secret.data.iter_mut().flat_map(|data| data.values_mut()) {
+        *value = ByteString(hash_key(&String::from_utf8_lossy(&value.0)).into_bytes());
+    }
+}
+
+/// Ports the operator hasn't provisioned a Secret for yet keep accepting the token of their spec.
+/// Both the ports and the secrets come from the stores, so the consumer only gets hashes.
+fn build_consumer(secrets: &Store<Secret>, crd: &TrpPort) -> Consumer {
+    let spec_token = crd.spec.auth_token.clone();
+
+    let secret_name = &crd.status.as_ref().unwrap().auth_token_secret;
+    if secret_name.is_empty() {
+        return Consumer::new(crd, spec_token.as_deref(), None);
+    }
+
+    let secret_ref = ObjectRef::new(secret_name).within(&crd.namespace().unwrap());
+    let secret = secrets.get(&secret_ref);
+    let auth_token = secret.as_deref().and_then(read_auth_token).or(spec_token);
+
+    Consumer::new(crd, auth_token.as_deref(), secret.as_deref())
+}
+
+/// Port that owns an auth secret, once the operator added its status.
+fn find_secret_port(ports: &Store<TrpPort>, secret: &Secret) -> Option<Arc<TrpPort>> {
+    let namespace = secret.namespace()?;
+    let port_name = secret.labels().get(TRP_PORT_LABEL)?;
+
+    ports
+        .get(&ObjectRef::new(port_name).within(&namespace))
+        .filter(|crd| crd.status.is_some())
+}
+
+// Events are handled one at a time as they arrive, so their size difference doesn't matter.
+#[allow(clippy::large_enum_variant)]
+enum WatchEvent {
+    Port(Result<Event<TrpPort>, watcher::Error>),
+    Secret(Result<Event<Secret>, watcher::Error>),
 }
 
 #[async_trait]
@@ -32,52 +111,70 @@ impl BackgroundService for AuthBackgroundService {
             .await
             .expect("failed to create kube client");
 
+        let (ports, ports_writer) = reflector::store();
         let api = Api::<TrpPort>::all(client.clone());
-        let stream = watcher::watcher(api.clone(), ConfigWatcher::default());
+        let port_events = reflector::reflector(
+            ports_writer,
+            watcher::watcher(api, ConfigWatcher::default()).modify(hash_port_tokens),
+        )
+        .map(WatchEvent::Port);
+
+        let (secrets, secrets_writer) = reflector::store();
+        let secrets_api = Api::<Secret>::all(client.clone());
+        let secret_events = reflector::reflector(
+            secrets_writer,
+            watcher::watcher(secrets_api, ConfigWatcher::default().labels(TRP_PORT_LABEL))
+                .modify(hash_secret_tokens),
+        )
+        .map(WatchEvent::Secret);
+
+        let stream = stream::select(port_events, secret_events);
         pin!(stream);
 
+        // Consumers are only built once both stores are listed, so tokens are never resolved
+        // against a partial list of secrets.
+        let mut ports_synced = false;
+        let mut secrets_synced = false;
+
         loop {
-            let result = stream.try_next().await;
+            let result = stream.next().await;
             match result {
-                // Stream restart, also run on startup.
-                Ok(Some(Event::Init)) => {
-                    info!("auth: Watcher restarted, reseting consumers");
-                    self.state.consumers.write().await.clear();
-                    self.state.limiter.write().await.clear();
+                // Stream restart, also run on startup. The current consumers keep being served
+                // until the listing finishes.
+                Some(WatchEvent::Port(Ok(Event::Init))) => {
+                    info!("auth: Port watcher restarted");
+                    ports_synced = false;
                 }
-                Ok(Some(Event::InitApply(crd))) => match crd.status {
-                    Some(_) => {
-                        info!(
-                            "auth: Adding consumer after stream restart: {}",
-                            crd.name_any()
-                        );
-                        let consumer = Consumer::from(&crd);
-                        self.state.limiter.write().await.remove(&consumer.key);
-                        self.state
-                            .consumers
-                            .write()
-                            .await
-                            .insert(consumer.key.clone(), consumer);
+                Some(WatchEvent::Secret(Ok(Event::Init))) => {
+                    info!("auth: Secret watcher restarted");
+                    secrets_synced = false;
+                }
+                Some(WatchEvent::Port(Ok(Event::InitApply(_))))
+                | Some(WatchEvent::Secret(Ok(Event::InitApply(_)))) => {}
+                Some(WatchEvent::Port(Ok(Event::InitDone))) => {
+                    ports_synced = true;
+                    if secrets_synced {
+                        self.load_consumers(&ports, &secrets).await;
                     }
-                    None => {
-                        // New ports are created without status. When the status is added, a new
-                        // Applied event is triggered.
-                        info!("auth: New port created: {}", crd.name_any());
+                }
+                Some(WatchEvent::Secret(Ok(Event::InitDone))) => {
+                    secrets_synced = true;
+                    if ports_synced {
+                        self.load_consumers(&ports, &secrets).await;
                     }
-                },
-                Ok(Some(Event::InitDone)) => info!("auth: Watcher restart finished"),
+                }
+
+                // Changes before both stores are listed are picked up when the consumers are
+                // rebuilt.
+                Some(WatchEvent::Port(Ok(_)) | WatchEvent::Secret(Ok(_)))
+                    if !(ports_synced && secrets_synced) => {}
 
                 // New port created or updated.
-                Ok(Some(Event::Apply(crd))) => match crd.status {
+                Some(WatchEvent::Port(Ok(Event::Apply(crd)))) => match crd.status {
                     Some(_) => {
                         info!("auth: Updating consumer: {}", crd.name_any());
-                        let consumer = Consumer::from(&crd);
-                        self.state.limiter.write().await.remove(&consumer.key);
-                        self.state
-                            .consumers
-                            .write()
-                            .await
-                            .insert(consumer.key.clone(), consumer);
+                        let consumer = build_consumer(&secrets, &crd);
+                        self.state.upsert_consumer(consumer).await;
                     }
                     None => {
                         // New ports are created without status. When the status is added, a new
@@ -86,22 +183,35 @@ impl BackgroundService for AuthBackgroundService {
                     }
                 },
                 // Port deleted.
-                Ok(Some(Event::Delete(crd))) => {
+                Some(WatchEvent::Port(Ok(Event::Delete(crd)))) => {
                     info!(
                         "auth: Port deleted, removing from state: {}",
                         crd.name_any()
                     );
-                    let consumer = Consumer::from(&crd);
-                    self.state.consumers.write().await.remove(&consumer.key);
-                    self.state.limiter.write().await.remove(&consumer.key);
+                    self.state
+                        .remove_consumer(&Consumer::new(&crd, None, None))
+                        .await;
+                }
+
+                // Auth secret created, rotated or deleted, the store already holds the change.
+                Some(WatchEvent::Secret(Ok(Event::Apply(secret) | Event::Delete(secret)))) => {
+                    if let Some(crd) = find_secret_port(&ports, &secret) {
+                        info!(
+                            "auth: Auth secret changed, updating consumer: {}",
+                            crd.name_any()
+                        );
+                        let consumer = build_consumer(&secrets, &crd);
+                        self.state.upsert_consumer(consumer).await;
+                    }
                 }
+
                 // Empty response from stream. Should never happen.
-                Ok(None) => {
+                None => {
                     error!("auth: Empty response from watcher.");
                     continue;
                 }
-                // Unexpected error when streaming CRDs.
-                Err(err) => {
+                // Unexpected error when streaming CRDs or secrets.
+                Some(WatchEvent::Port(Err(err)) | WatchEvent::Secret(Err(err))) => {
                     error!(error = err.to_string(), "auth: Failed to update crds.");
                     std::process::exit(1);
                 }
@@ -109,3 +219,48 @@ impl BackgroundService for AuthBackgroundService {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use operator::read_auth_key;
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn caches_only_token_hashes() {
+        let mut crd: TrpPort = serde_json::from_value(json!({
+            "apiVersion": "demeter.run/v1beta1",
+            "kind": "TrpPort",
+            "metadata": {
+                "name": "port",
+                "namespace": "prj-test",
+                "annotations": { LAST_APPLIED_ANNOTATION: "{\"authToken\":\"token\"}" },
+            },
+            "spec": {
+                "network": "cardano-mainnet",
+                "throughputTier": "0",
+                "authToken": "token",
+            },
+        }))
+        .unwrap();
+        hash_port_tokens(&mut crd);
+
+        assert_eq!(crd.spec.auth_token, Some(hash_key("token")));
+        assert!(crd.annotations().get(LAST_APPLIED_ANNOTATION).is_none());
+
+        let mut secret = Secret {
+            data: Some(BTreeMap::from([
+                ("token".to_string(), ByteString(b"token".to_vec())),
+                ("key.old".to_string(), ByteString(b"old-token".to_vec())),
+            ])),
+            ..Default::default()
+        };
+        hash_secret_tokens(&mut secret);
+
+        assert_eq!(read_auth_token(&secret), Some(hash_key("token")));
+        assert_eq!(read_auth_key(&secret, "old"), Some(hash_key("old-token")));
+    }
+}
@@ -0,0 +1,197 @@
+use pingora::http::{Method, RequestHeader};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+
+use crate::Tier;
+
+/// Largest request body parsed by the proxy. Matches the retry buffer of pingora, which keeps the
+/// body read before proxying so it can still be sent to the instance.
+pub const MAX_BODY_SIZE: usize = 64 * 1024;
+
+/// Methods of the TRP spec, recorded as metric labels even when no tier names them.
+const TRP_METHODS: &[&str] = &["trp.resolve", "trp.submit", "trp.checkStatus"];
+
+pub const PARSE_ERROR: i64 = -32700;
+
+// Application error codes, outside of the range reserved by the JSON-RPC spec.
+pub const AUTH_ERROR: i64 = -32001;
+pub const FORBIDDEN_ERROR: i64 = -32002;
+pub const RATE_LIMIT_ERROR: i64 = -32003;
+pub const QUOTA_ERROR: i64 = -32004;
+pub const UPSTREAM_ERROR: i64 = -32005;
+pub const METHOD_NOT_ALLOWED_ERROR: i64 = -32006;
+pub const BATCH_TOO_LARGE_ERROR: i64 = -32007;
+pub const BODY_TOO_LARGE_ERROR: i64 = -32008;
+
+/// TRP clients send JSON-RPC as JSON POST requests, anything else keeps plain HTTP errors.
+pub fn is_json_rpc(req: &RequestHeader) -> bool {
+    req.method == Method::POST
+        && req
+            .headers
+            .get("content-type")
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|v| v.starts_with("application/json"))
+}
+
+#[derive(Debug, Clone)]
+pub struct Call {
+    pub id: Value,
+    pub method: String,
+}
+impl Call {
+    fn from_value(value: &Value) -> Self {
+        Self {
+            id: value.get("id").cloned().unwrap_or(Value::Null),
+            method: value
+                .get("method")
+                .and_then(Value::as_str)
+                .unwrap_or_default()
+                .to_string(),
+        }
+    }
+}
+
+/// JSON-RPC request, either a single call or a batch of calls.
+#[derive(Debug, Clone)]
+pub enum Envelope {
+    Single(Call),
+    Batch(Vec<Call>),
+}
+impl Envelope {
+    pub fn parse(body: &[u8]) -> Option<Self> {
+        match serde_json::from_slice::<Value>(body).ok()? {
+            Value::Array(calls) => Some(Envelope::Batch(
+                calls.iter().map(Call::from_value).collect(),
+            )),
+            value @ Value::Object(_) => Some(Envelope::Single(Call::from_value(&value))),
+            _ => None,
+        }
+    }
+
+    pub fn calls(&self) -> &[Call] {
+        match self {
+            Envelope::Single(call) => std::slice::from_ref(call),
+            Envelope::Batch(calls) => calls,
+        }
+    }
+
+    /// Number of requests the envelope counts as, one per call of a batch.
+    pub fn requests(&self) -> usize {
+        self.calls().len().max(1)
+    }
+}
+
+/// Method names come from clients, only the TRP methods and the methods named by the tiers are
+/// used as metric labels so a client can't create arbitrary series.
+pub fn method_label<'a>(method: &'a str, tiers: &HashMap<String, Tier>) -> &'a str {
+    let known =
+        TRP_METHODS.contains(&method) || tiers.values().any(|tier| tier.names_method(method));
+
+    if known {
+        method
+    } else {
+        "other"
+    }
+}
+
+/// Error answering the whole request. Batches get the error once per call, requests that couldn't
+/// be parsed get it with a `null` id.
+pub fn error_response(envelope: Option<&Envelope>, code: i64, message: &str, data: Value) -> Value {
+    match envelope {
+        Some(Envelope::Batch(calls)) if !calls.is_empty() => calls
+            .iter()
+            .map(|call| error_body(call.id.clone(), code, message, data.clone()))
+            .collect(),
+        Some(Envelope::Single(call)) => error_body(call.id.clone(), code, message, data),
+        _ => error_body(Value::Null, code, message, data),
+    }
+}
+
+pub fn error_body(id: Value, code: i64, message: &str, data: Value) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "error": {
+            "code": code,
+            "message": message,
+            "data": data,
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_single_calls() {
+        let envelope = Envelope::parse(br#"{"jsonrpc":"2.0","id":7,"method":"trp.resolve"}"#);
+        let Some(Envelope::Single(call)) = envelope else {
+            panic!("expected a single call");
+        };
+        assert_eq!(call.id, json!(7));
+        assert_eq!(call.method, "trp.resolve");
+    }
+
+    #[test]
+    fn parses_batches() {
+        let envelope =
+            Envelope::parse(br#"[{"id":1,"method":"trp.resolve"},{"id":2,"method":"trp.submit"}]"#)
+                .unwrap();
+        assert!(matches!(envelope, Envelope::Batch(_)));
+        assert_eq!(envelope.calls().len(), 2);
+        assert_eq!(envelope.requests(), 2);
+    }
+
+    #[test]
+    fn counts_empty_batches_as_one_request() {
+        let envelope = Envelope::parse(b"[]").unwrap();
+        assert!(envelope.calls().is_empty());
+        assert_eq!(envelope.requests(), 1);
+    }
+
+    #[test]
+    fn keeps_calls_without_method() {
+        let envelope = Envelope::parse(br#"[{"id":1},"call"]"#).unwrap();
+        let methods: Vec<_> = envelope.calls().iter().map(|c| c.method.as_str()).collect();
+        assert_eq!(methods, ["", ""]);
+    }
+
+    #[test]
+    fn rejects_invalid_bodies() {
+        assert!(Envelope::parse(b"").is_none());
+        assert!(Envelope::parse(b"{\"id\":").is_none());
+        assert!(Envelope::parse(b"42").is_none());
+        assert!(Envelope::parse(b"\"trp.resolve\"").is_none());
+    }
+
+    #[test]
+    fn answers_batches_once_per_call() {
+        let envelope =
+            Envelope::parse(br#"[{"id":1,"method":"trp.resolve"},{"id":"b","method":"x"}]"#)
+                .unwrap();
+        let response = error_response(Some(&envelope), RATE_LIMIT_ERROR, "limited", Value::Null);
+
+        let ids: Vec<_> = response
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|item| item["id"].clone())
+            .collect();
+        assert_eq!(ids, [json!(1), json!("b")]);
+    }
+
+    #[test]
+    fn answers_unparsed_requests_with_null_id() {
+        let response = error_response(None, PARSE_ERROR, "invalid", Value::Null);
+        assert_eq!(response["id"], Value::Null);
+        assert_eq!(response["error"]["code"], json!(PARSE_ERROR));
+    }
+
+    #[test]
+    fn labels_only_known_methods() {
+        let tiers = HashMap::new();
+        assert_eq!(method_label("trp.resolve", &tiers), "trp.resolve");
+        assert_eq!(method_label("eth_random_123", &tiers), "other");
+    }
+}
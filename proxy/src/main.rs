@@ -1,7 +1,9 @@
 use auth::AuthBackgroundService;
+use chrono::{DateTime, Utc};
 use config::Config;
 use dotenv::dotenv;
-use operator::{kube::ResourceExt, TrpPort};
+use jsonrpc::Envelope;
+use operator::{hash_key, k8s_openapi::api::core::v1::Secret, kube::ResourceExt, TrpPort};
 use pingora::{
     server::{configuration::Opt, Server},
     services::background::background_service,
@@ -18,6 +20,7 @@ use tracing::Level;
 
 mod auth;
 mod config;
+mod jsonrpc;
 mod proxy;
 mod tiers;
 
@@ -70,6 +73,7 @@ fn main() {
 
 #[derive(Default)]
 pub struct State {
+    // Indexed by the SHA-256 hash of the api key, never by the key itself.
     consumers: RwLock<HashMap<String, Consumer>>,
     tiers: RwLock<HashMap<String, Tier>>,
     limiter: RwLock<HashMap<String, Vec<(TierRate, Rate)>>>,
@@ -77,8 +81,56 @@ pub struct State {
 }
 impl State {
     pub async fn get_consumer(&self, key: &str) -> Option<Consumer> {
-        let consumers = self.consumers.read().await.clone();
-        consumers.get(key).cloned()
+        let hash = hash_key(key);
+        let consumer = self.consumers.read().await.get(&hash).cloned()?;
+        consumer
+            .is_key_active(&hash, Utc::now())
+            .then_some(consumer)
+    }
+
+    /// Replaces every key of the consumer's port, so rotated keys stop being accepted. The
+    /// limiter is only reset when the tier changed, status updates keep the current rates.
+    pub async fn upsert_consumer(&self, consumer: Consumer) {
+        let mut consumers = self.consumers.write().await;
+        let tier_changed = match consumers.values().find(|c| c.id() == consumer.id()) {
+            Some(current) => current.tier != consumer.tier,
+            None => true,
+        };
+
+        consumers.retain(|_, c| c.id() != consumer.id());
+        for key in consumer.keys.iter() {
+            consumers.insert(key.hash.clone(), consumer.clone());
+        }
+
+        if tier_changed {
+            self.limiter.write().await.remove(&consumer.id());
+        }
+    }
+
+    /// Replaces every consumer, like after a watcher restart. Keys stay served until the new
+    /// consumers are swapped in.
+    pub async fn replace_consumers(&self, consumers: Vec<Consumer>) {
+        let consumers = consumers
+            .into_iter()
+            .flat_map(|consumer| {
+                consumer
+                    .keys
+                    .iter()
+                    .map(|key| (key.hash.clone(), consumer.clone()))
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+
+        *self.consumers.write().await = consumers;
+        self.limiter.write().await.clear();
+    }
+
+    pub async fn remove_consumer(&self, consumer: &Consumer) {
+        self.consumers
+            .write()
+            .await
+            .retain(|_, c| c.id() != consumer.id());
+        self.limiter.write().await.remove(&consumer.id());
     }
 }
 
@@ -87,36 +139,159 @@ pub struct Consumer {
     namespace: String,
     port_name: String,
     tier: String,
-    key: String,
     network: String,
+    keys: Vec<ConsumerKey>,
+    quota_exceeded_until: Option<DateTime<Utc>>,
+    suspended: bool,
+    suspend_reason: Option<String>,
 }
-impl Display for Consumer {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}.{}", self.namespace, self.port_name)
-    }
-}
-impl From<&TrpPort> for Consumer {
-    fn from(value: &TrpPort) -> Self {
-        let network = value.spec.network.to_string();
-        let tier = value.spec.throughput_tier.to_string();
-        let key = value.status.as_ref().unwrap().auth_token.clone();
-        let namespace = value.metadata.namespace.as_ref().unwrap().clone();
-        let port_name = value.name_any();
+impl Consumer {
+    /// Builds the consumer of a port. The auth token and the rotation keys live in the port
+    /// Secret, so they're given separately from the port spec. The token and the Secret only hold
+    /// key hashes, the watcher hashes them before they're cached.
+    pub fn new(port: &TrpPort, auth_token_hash: Option<&str>, secret: Option<&Secret>) -> Self {
+        let network = port.spec.network.to_string();
+        let tier = port.spec.throughput_tier.to_string();
+        let keys = port
+            .spec
+            .active_keys(auth_token_hash, secret, Utc::now())
+            .iter()
+            .map(|key| ConsumerKey {
+                hash: key.token.clone(),
+                not_after: key.not_after,
+            })
+            .collect();
+        let quota_exceeded_until = port
+            .status
+            .as_ref()
+            .and_then(|status| status.quota_exceeded_until());
+        let namespace = port.metadata.namespace.as_ref().unwrap().clone();
+        let port_name = port.name_any();
 
         Self {
             namespace,
             port_name,
             tier,
-            key,
             network,
+            keys,
+            quota_exceeded_until,
+            suspended: port.spec.suspended,
+            suspend_reason: port.spec.suspend_reason.clone(),
         }
     }
+
+    /// Identifies the port behind the consumer. All of its keys share the same limiter.
+    pub fn id(&self) -> String {
+        self.to_string()
+    }
+
+    pub fn is_key_active(&self, hash: &str, now: DateTime<Utc>) -> bool {
+        self.keys
+            .iter()
+            .any(|k| k.hash == hash && k.not_after.is_none_or(|not_after| not_after > now))
+    }
+
+    /// The quota resets when the billing period ends, even before the operator updates the port.
+    pub fn is_over_quota(&self, now: DateTime<Utc>) -> bool {
+        self.quota_exceeded_until.is_some_and(|until| now < until)
+    }
+}
+impl Display for Consumer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}", self.namespace, self.port_name)
+    }
+}
+#[derive(Debug, Clone)]
+pub struct ConsumerKey {
+    hash: String,
+    not_after: Option<DateTime<Utc>>,
 }
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct Tier {
     name: String,
     rates: Vec<TierRate>,
+    /// JSON-RPC methods the tier can call, every method when empty.
+    #[serde(default)]
+    allowed_methods: Vec<String>,
+    #[serde(default)]
+    denied_methods: Vec<String>,
+    /// Cost of a request in the rate limiter and in the usage units, by JSON-RPC method.
+    #[serde(default)]
+    method_costs: HashMap<String, u64>,
+    #[serde(default = "default_method_cost")]
+    default_method_cost: u64,
+    /// Most calls a JSON-RPC batch can have, unlimited when unset.
+    #[serde(default)]
+    max_batch_size: Option<usize>,
+}
+fn default_method_cost() -> u64 {
+    1
+}
+impl Tier {
+    /// Batches cost the sum of their calls. Requests whose calls are unknown cost the default.
+    pub fn cost(&self, envelope: Option<&Envelope>) -> u64 {
+        match envelope.map(Envelope::calls) {
+            Some(calls) if !calls.is_empty() => calls
+                .iter()
+                .map(|call| self.method_cost(&call.method))
+                .sum(),
+            _ => self.default_method_cost,
+        }
+    }
+
+    fn method_cost(&self, method: &str) -> u64 {
+        self.method_costs
+            .get(method)
+            .copied()
+            .unwrap_or(self.default_method_cost)
+    }
+
+    /// Tiers that restrict, price or batch calls need the calls of a request to admit and charge
+    /// it, so they reject requests whose body couldn't be parsed.
+    pub fn needs_envelope(&self) -> bool {
+        self.restricts_methods() || self.max_batch_size.is_some() || !self.method_costs.is_empty()
+    }
+
+    pub fn exceeds_batch_size(&self, size: usize) -> bool {
+        self.max_batch_size.is_some_and(|max| size > max)
+    }
+
+    /// Whether the tier configures the method, in its method lists or costs.
+    pub fn names_method(&self, method: &str) -> bool {
+        self.allowed_methods.iter().any(|m| m == method)
+            || self.denied_methods.iter().any(|m| m == method)
+            || self.method_costs.contains_key(method)
+    }
+
+    pub fn restricts_methods(&self) -> bool {
+        !self.allowed_methods.is_empty() || !self.denied_methods.is_empty()
+    }
+
+    pub fn allows_method(&self, method: &str) -> bool {
+        let allowed =
+            self.allowed_methods.is_empty() || self.allowed_methods.iter().any(|m| m == method);
+        allowed && !self.denied_methods.iter().any(|m| m == method)
+    }
+
+    /// Returns why the calls of a request aren't allowed on the tier. Requests without a body,
+    /// like GET requests, are rejected by tiers that restrict methods, since their methods can't
+    /// be checked.
+    pub fn check_methods(&self, envelope: Option<&Envelope>) -> Option<String> {
+        if !self.restricts_methods() {
+            return None;
+        }
+
+        let Some(envelope) = envelope else {
+            return Some(format!("Tier {} only accepts JSON-RPC requests", self.name));
+        };
+
+        envelope
+            .calls()
+            .iter()
+            .find(|call| !self.allows_method(&call.method))
+            .map(|call| format!("Method {} not allowed for tier {}", call.method, self.name))
+    }
 }
 #[derive(Debug, Clone, Deserialize)]
 pub struct TierRate {
@@ -154,11 +329,30 @@ pub fn deserialize_duration<'de, D: Deserializer<'de>>(
 #[derive(Debug, Clone)]
 pub struct Metrics {
     http_total_request: prometheus::IntCounterVec,
+    http_total_units: prometheus::IntCounterVec,
+    jsonrpc_calls: prometheus::IntCounterVec,
 }
 impl Metrics {
     pub fn new() -> Self {
         let http_total_request = register_int_counter_vec!(
             opts!("trp_proxy_http_total_request", "Total http request",),
+            &[
+                "consumer",
+                "namespace",
+                "instance",
+                "status_code",
+                "network",
+                "tier",
+                "reason"
+            ]
+        )
+        .unwrap();
+
+        let http_total_units = register_int_counter_vec!(
+            opts!(
+                "trp_proxy_http_total_units",
+                "Total http request units, weighted by method cost",
+            ),
             &[
                 "consumer",
                 "namespace",
@@ -170,7 +364,17 @@ impl Metrics {
         )
         .unwrap();
 
-        Self { http_total_request }
+        let jsonrpc_calls = register_int_counter_vec!(
+            opts!("trp_proxy_jsonrpc_calls_total", "Total JSON-RPC calls",),
+            &["consumer", "method", "status_code", "network", "tier"]
+        )
+        .unwrap();
+
+        Self {
+            http_total_request,
+            http_total_units,
+            jsonrpc_calls,
+        }
     }
 
     pub fn inc_http_total_request(
@@ -179,6 +383,8 @@ impl Metrics {
         namespace: &str,
         instance: &str,
         status: &u16,
+        reason: &str,
+        requests: u64,
     ) {
         self.http_total_request
             .with_label_values(&[
@@ -188,6 +394,39 @@ impl Metrics {
                 &status.to_string(),
                 &consumer.network,
                 &consumer.tier,
+                reason,
+            ])
+            .inc_by(requests)
+    }
+
+    pub fn inc_http_total_units(
+        &self,
+        consumer: &Consumer,
+        namespace: &str,
+        instance: &str,
+        status: &u16,
+        units: u64,
+    ) {
+        self.http_total_units
+            .with_label_values(&[
+                &consumer.to_string(),
+                namespace,
+                instance,
+                &status.to_string(),
+                &consumer.network,
+                &consumer.tier,
+            ])
+            .inc_by(units)
+    }
+
+    pub fn inc_jsonrpc_call(&self, consumer: &Consumer, method: &str, status: &u16) {
+        self.jsonrpc_calls
+            .with_label_values(&[
+                &consumer.to_string(),
+                method,
+                &status.to_string(),
+                &consumer.network,
+                &consumer.tier,
             ])
             .inc()
     }
@@ -197,3 +436,192 @@ impl Default for Metrics {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn tier() -> Tier {
+        serde_json::from_value(json!({
+            "name": "1",
+            "rates": [{ "interval": "1m", "limit": 100 }],
+            "method_costs": { "trp.submit": 5 },
+            "default_method_cost": 2,
+            "max_batch_size": 3,
+        }))
+        .unwrap()
+    }
+
+    fn envelope(body: &str) -> Envelope {
+        Envelope::parse(body.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn charges_single_calls_by_method() {
+        let tier = tier();
+
+        let submit = envelope(r#"{"jsonrpc":"2.0","id":1,"method":"trp.submit"}"#);
+        assert_eq!(tier.cost(Some(&submit)), 5);
+
+        let resolve = envelope(r#"{"jsonrpc":"2.0","id":1,"method":"trp.resolve"}"#);
+        assert_eq!(tier.cost(Some(&resolve)), 2);
+    }
+
+    #[test]
+    fn charges_batches_per_call() {
+        let batch = envelope(
+            r#"[{"id":1,"method":"trp.submit"},{"id":2,"method":"trp.resolve"},{"id":3,"method":"trp.submit"}]"#,
+        );
+        assert_eq!(tier().cost(Some(&batch)), 12);
+    }
+
+    #[test]
+    fn charges_the_default_without_calls() {
+        let tier = tier();
+        assert_eq!(tier.cost(None), 2);
+        assert_eq!(tier.cost(Some(&envelope("[]"))), 2);
+    }
+
+    #[test]
+    fn defaults_to_one_unit_per_call() {
+        let tier: Tier = serde_json::from_value(json!({
+            "name": "0",
+            "rates": [{ "interval": "1m", "limit": 100 }],
+        }))
+        .unwrap();
+
+        let batch = envelope(r#"[{"id":1,"method":"trp.submit"},{"id":2,"method":"trp.resolve"}]"#);
+        assert_eq!(tier.cost(Some(&batch)), 2);
+        assert!(!tier.exceeds_batch_size(1000));
+        assert!(!tier.needs_envelope());
+    }
+
+    #[test]
+    fn caps_batch_size() {
+        let tier = tier();
+        assert!(!tier.exceeds_batch_size(3));
+        assert!(tier.exceeds_batch_size(4));
+        assert!(tier.needs_envelope());
+    }
+
+    #[test]
+    fn batch_caps_need_the_envelope() {
+        let tier: Tier = serde_json::from_value(json!({
+            "name": "0",
+            "rates": [{ "interval": "1m", "limit": 100 }],
+            "max_batch_size": 3,
+        }))
+        .unwrap();
+
+        assert!(tier.needs_envelope());
+    }
+
+    #[test]
+    fn denied_methods_take_precedence() {
+        let tier: Tier = serde_json::from_value(json!({
+            "name": "1",
+            "rates": [],
+            "allowed_methods": ["trp.resolve", "trp.submit"],
+            "denied_methods": ["trp.submit"],
+        }))
+        .unwrap();
+
+        assert!(tier.restricts_methods());
+        assert!(tier.allows_method("trp.resolve"));
+        assert!(!tier.allows_method("trp.submit"));
+        assert!(!tier.allows_method("trp.dump"));
+    }
+
+    #[test]
+    fn empty_allow_list_allows_every_method() {
+        let denying: Tier = serde_json::from_value(json!({
+            "name": "1",
+            "rates": [],
+            "denied_methods": ["trp.submit"],
+        }))
+        .unwrap();
+        assert!(denying.allows_method("trp.resolve"));
+        assert!(!denying.allows_method("trp.submit"));
+
+        let unrestricted = tier();
+        assert!(!unrestricted.restricts_methods());
+        assert!(unrestricted.allows_method("trp.dump"));
+        assert_eq!(unrestricted.check_methods(None), None);
+    }
+
+    #[test]
+    fn restricting_tiers_check_every_call() {
+        let tier: Tier = serde_json::from_value(json!({
+            "name": "1",
+            "rates": [],
+            "allowed_methods": ["trp.resolve"],
+        }))
+        .unwrap();
+
+        let resolve = envelope(r#"{"jsonrpc":"2.0","id":1,"method":"trp.resolve"}"#);
+        assert_eq!(tier.check_methods(Some(&resolve)), None);
+
+        let batch = envelope(r#"[{"id":1,"method":"trp.resolve"},{"id":2,"method":"trp.submit"}]"#);
+        assert_eq!(
+            tier.check_methods(Some(&batch)).as_deref(),
+            Some("Method trp.submit not allowed for tier 1")
+        );
+        assert_eq!(
+            tier.check_methods(None).as_deref(),
+            Some("Tier 1 only accepts JSON-RPC requests")
+        );
+    }
+
+    #[test]
+    fn quota_resets_at_the_end_of_the_billing_period() {
+        let port: TrpPort = serde_json::from_value(json!({
+            "apiVersion": "demeter.run/v1beta1",
+            "kind": "TrpPort",
+            "metadata": { "name": "port", "namespace": "prj-test" },
+            "spec": { "network": "cardano-mainnet", "throughputTier": "0" },
+            "status": {
+                "endpointUrl": "https://trp.example",
+                "conditions": [{
+                    "type": "QuotaExceeded",
+                    "status": "True",
+                    "reason": "QuotaExceeded",
+                    "message": "Used 10 of 10 units in the billing period",
+                    "lastTransitionTime": "2026-01-20T00:00:00Z",
+                    "observedGeneration": 1,
+                }],
+                "usage": {
+                    "dayStart": "2026-01-20T00:00:00Z",
+                    "unitsToday": 10,
+                    "billingPeriodStart": "2026-01-01T00:00:00Z",
+                    "unitsBillingPeriod": 10,
+                    "lastUpdated": "2026-01-20T01:00:00Z",
+                },
+            },
+        }))
+        .unwrap();
+        let consumer = Consumer::new(&port, Some("hash"), None);
+
+        let at = |timestamp: &str| timestamp.parse::<DateTime<Utc>>().unwrap();
+        assert!(consumer.is_over_quota(at("2026-01-31T23:59:59Z")));
+        assert!(!consumer.is_over_quota(at("2026-02-01T00:00:00Z")));
+
+        let port = TrpPort {
+            status: None,
+            ..port
+        };
+        let consumer = Consumer::new(&port, Some("hash"), None);
+        assert!(!consumer.is_over_quota(at("2026-01-31T23:59:59Z")));
+    }
+
+    #[test]
+    fn rejects_negative_costs() {
+        let result = serde_json::from_value::<Tier>(json!({
+            "name": "1",
+            "rates": [],
+            "method_costs": { "trp.submit": -5 },
+        }));
+        assert!(result.is_err());
+    }
+}
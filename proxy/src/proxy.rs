@@ -1,19 +1,95 @@
 use async_trait::async_trait;
+use chrono::Utc;
 use pingora::http::Method;
-use pingora::Result;
 use pingora::{
     http::ResponseHeader,
     proxy::{ProxyHttp, Session},
     upstreams::peer::HttpPeer,
 };
+use pingora::{Error, ErrorSource, ErrorType, Result};
 use pingora_limits::rate::Rate;
+use serde_json::json;
 use std::sync::Arc;
-use tracing::info;
+use tracing::{error, info};
 
 use crate::config::Config;
+use crate::jsonrpc::{self, Envelope};
 use crate::{Consumer, State, Tier};
 
 static DMTR_API_KEY: &str = "dmtr-api-key";
+static REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Why a request was answered by the proxy instead of the instance.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Rejection {
+    MissingKey,
+    InvalidKey,
+    WrongNetwork,
+    Suspended,
+    QuotaExceeded,
+    MethodNotAllowed,
+    InvalidBody,
+    BodyTooLarge,
+    BatchTooLarge,
+    RateLimited,
+}
+impl Rejection {
+    pub fn status(&self) -> u16 {
+        match self {
+            Rejection::MissingKey | Rejection::InvalidKey => 401,
+            Rejection::WrongNetwork | Rejection::Suspended | Rejection::MethodNotAllowed => 403,
+            Rejection::InvalidBody => 400,
+            Rejection::BodyTooLarge | Rejection::BatchTooLarge => 413,
+            Rejection::QuotaExceeded | Rejection::RateLimited => 429,
+        }
+    }
+
+    /// Stable code for clients and for the `reason` metric label.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Rejection::MissingKey => "missing_key",
+            Rejection::InvalidKey => "invalid_key",
+            Rejection::WrongNetwork => "wrong_network",
+            Rejection::Suspended => "port_suspended",
+            Rejection::QuotaExceeded => "quota_exceeded",
+            Rejection::MethodNotAllowed => "method_not_allowed",
+            Rejection::InvalidBody => "invalid_body",
+            Rejection::BodyTooLarge => "body_too_large",
+            Rejection::BatchTooLarge => "batch_too_large",
+            Rejection::RateLimited => "rate_limited",
+        }
+    }
+
+    fn message(&self) -> &'static str {
+        match self {
+            Rejection::MissingKey => "The dmtr-api-key header is missing",
+            Rejection::InvalidKey => "The api key is not valid",
+            Rejection::WrongNetwork => "The api key belongs to a port of another network",
+            Rejection::Suspended => "The port is suspended",
+            Rejection::QuotaExceeded => "The monthly quota of the port is used up",
+            Rejection::MethodNotAllowed => "Method not allowed for tier",
+            Rejection::InvalidBody => "The JSON-RPC request couldn't be parsed",
+            Rejection::BodyTooLarge => {
+                "The JSON-RPC request is too large or doesn't have a Content-Length"
+            }
+            Rejection::BatchTooLarge => "The batch has more calls than the tier allows",
+            Rejection::RateLimited => "Too many requests for the tier of the port",
+        }
+    }
+
+    fn json_rpc_code(&self) -> i64 {
+        match self {
+            Rejection::MissingKey | Rejection::InvalidKey => jsonrpc::AUTH_ERROR,
+            Rejection::WrongNetwork | Rejection::Suspended => jsonrpc::FORBIDDEN_ERROR,
+            Rejection::QuotaExceeded => jsonrpc::QUOTA_ERROR,
+            Rejection::MethodNotAllowed => jsonrpc::METHOD_NOT_ALLOWED_ERROR,
+            Rejection::InvalidBody => jsonrpc::PARSE_ERROR,
+            Rejection::BodyTooLarge => jsonrpc::BODY_TOO_LARGE_ERROR,
+            Rejection::BatchTooLarge => jsonrpc::BATCH_TOO_LARGE_ERROR,
+            Rejection::RateLimited => jsonrpc::RATE_LIMIT_ERROR,
+        }
+    }
+}
 
 pub struct TrpProxy {
     state: Arc<State>,
@@ -26,7 +102,7 @@ impl TrpProxy {
 
     async fn has_limiter(&self, consumer: &Consumer) -> bool {
         let rate_limiter_map = self.state.limiter.read().await;
-        rate_limiter_map.get(&consumer.key).is_some()
+        rate_limiter_map.get(&consumer.id()).is_some()
     }
 
     async fn add_limiter(&self, consumer: &Consumer, tier: &Tier) {
@@ -40,10 +116,10 @@ impl TrpProxy {
             .limiter
             .write()
             .await
-            .insert(consumer.key.clone(), rates);
+            .insert(consumer.id(), rates);
     }
 
-    async fn limiter(&self, consumer: &Consumer) -> Result<bool> {
+    async fn limiter(&self, consumer: &Consumer, cost: u64) -> Result<bool> {
         let tiers = self.state.tiers.read().await.clone();
         let tier = tiers.get(&consumer.tier);
         if tier.is_none() {
@@ -56,24 +132,174 @@ impl TrpProxy {
         }
 
         let rate_limiter_map = self.state.limiter.read().await;
-        let rates = rate_limiter_map.get(&consumer.key).unwrap();
+        let id = consumer.id();
+        let rates = rate_limiter_map.get(&id).unwrap();
+        let cost = isize::try_from(cost).unwrap_or(isize::MAX);
 
-        if rates
-            .iter()
-            .any(|(t, r)| r.observe(&consumer.key, 1) > t.limit)
-        {
+        // Every rate is charged before checking, so concurrent requests can't all pass against
+        // the same count. A rejected request is refunded, so a rejected batch doesn't use up the
+        // budget left for smaller requests.
+        let mut limited = false;
+        for (t, r) in rates.iter() {
+            if r.observe(&id, cost) > t.limit {
+                limited = true;
+            }
+        }
+
+        if limited {
+            for (_, r) in rates.iter() {
+                r.observe(&id, -cost);
+            }
             return Ok(true);
         }
 
         Ok(false)
     }
 
+    async fn request_cost(&self, ctx: &Context) -> u64 {
+        self.state
+            .tiers
+            .read()
+            .await
+            .get(&ctx.consumer.tier)
+            .map(|tier| tier.cost(ctx.envelope.as_ref()))
+            .unwrap_or(1)
+    }
+
+    /// JSON-RPC bodies are read to record the called methods. Tiers that need the calls read the
+    /// body of every POST request whatever its content type, so a batch can't skip their checks.
+    async fn reads_body(&self, session: &Session, ctx: &Context) -> bool {
+        if ctx.is_json_rpc {
+            return true;
+        }
+
+        session.req_header().method == Method::POST
+            && self
+                .state
+                .tiers
+                .read()
+                .await
+                .get(&ctx.consumer.tier)
+                .is_some_and(Tier::needs_envelope)
+    }
+
+    /// JSON-RPC requests whose calls are unknown can't be charged by tiers that need them.
+    async fn check_body(&self, ctx: &Context) -> Option<Rejection> {
+        let rejection = ctx.body_rejection?;
+
+        let tiers = self.state.tiers.read().await;
+        let tier = tiers.get(&ctx.consumer.tier)?;
+        tier.needs_envelope().then_some(rejection)
+    }
+
+    async fn check_batch_size(&self, ctx: &Context) -> Option<String> {
+        let Some(Envelope::Batch(calls)) = &ctx.envelope else {
+            return None;
+        };
+
+        let tiers = self.state.tiers.read().await;
+        let tier = tiers.get(&ctx.consumer.tier)?;
+        tier.exceeds_batch_size(calls.len()).then(|| {
+            format!(
+                "Batch of {} calls exceeds the limit of tier {}",
+                calls.len(),
+                ctx.consumer.tier
+            )
+        })
+    }
+
+    /// Returns why the request can't call its methods on the tier of the consumer. Bodies that
+    /// couldn't be parsed are rejected before by `check_body`.
+    async fn check_methods(&self, ctx: &Context) -> Option<String> {
+        let tiers = self.state.tiers.read().await;
+        tiers
+            .get(&ctx.consumer.tier)?
+            .check_methods(ctx.envelope.as_ref())
+    }
+
     fn extract_key(&self, session: &Session) -> Option<String> {
         session
             .get_header(DMTR_API_KEY)
             .map(|v| v.to_str().unwrap().to_string())
     }
 
+    fn extract_request_id(&self, session: &Session) -> String {
+        session
+            .get_header(REQUEST_ID_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_string())
+            .unwrap_or_else(|| format!("{:032x}", rand::random::<u128>()))
+    }
+
+    /// Answers the request with a JSON error body, or a JSON-RPC error for JSON-RPC requests.
+    /// `message` replaces the default message of the rejection.
+    async fn respond_rejection(
+        &self,
+        session: &mut Session,
+        ctx: &mut Context,
+        rejection: Rejection,
+        message: Option<&str>,
+    ) -> Result<()> {
+        ctx.rejection = Some(rejection);
+        let message = message.unwrap_or(rejection.message());
+
+        let body = if ctx.is_json_rpc {
+            let data = json!({ "reason": rejection.code(), "request_id": ctx.request_id });
+            jsonrpc::error_response(
+                ctx.envelope.as_ref(),
+                rejection.json_rpc_code(),
+                message,
+                data,
+            )
+        } else {
+            json!({
+                "code": rejection.code(),
+                "message": message,
+                "request_id": ctx.request_id,
+            })
+        };
+
+        self.respond_json(session, ctx, rejection.status(), body)
+            .await
+    }
+
+    /// Auth failures aren't proxied, so the body of a JSON-RPC request is read to echo its id
+    /// back. Bodies that are too large or can't be parsed are answered with a null id.
+    async fn reject_unauthenticated(
+        &self,
+        session: &mut Session,
+        ctx: &mut Context,
+        rejection: Rejection,
+    ) -> Result<()> {
+        if ctx.is_json_rpc {
+            if let Ok(Ok(envelope)) = read_envelope(session).await {
+                ctx.envelope = Some(envelope);
+            }
+        }
+
+        self.respond_rejection(session, ctx, rejection, None).await
+    }
+
+    async fn respond_json(
+        &self,
+        session: &mut Session,
+        ctx: &Context,
+        status: u16,
+        body: serde_json::Value,
+    ) -> Result<()> {
+        let body = serde_json::to_vec(&body).unwrap();
+
+        let mut header = ResponseHeader::build(status, Some(4))?;
+        header.insert_header("Content-Type", "application/json")?;
+        header.insert_header("Content-Length", body.len().to_string())?;
+        header.insert_header(REQUEST_ID_HEADER, &ctx.request_id)?;
+        session.set_keepalive(None);
+        session
+            .write_response_header(Box::new(header), false)
+            .await?;
+        session.write_response_body(Some(body.into()), true).await
+    }
+
     async fn respond_health(&self, session: &mut Session, ctx: &mut Context) {
         ctx.is_health_request = true;
         session.set_keepalive(None);
@@ -86,11 +312,57 @@ impl TrpProxy {
     }
 }
 
+/// Reads and parses the JSON-RPC body. Only bodies with a known length that fits the retry buffer
+/// are read, the buffer is then sent to the instance as the request body. Returns why the body
+/// couldn't be parsed otherwise.
+async fn read_envelope(session: &mut Session) -> Result<std::result::Result<Envelope, Rejection>> {
+    let content_length = session
+        .get_header("content-length")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<usize>().ok());
+    if content_length.is_none_or(|length| length > jsonrpc::MAX_BODY_SIZE) {
+        return Ok(Err(Rejection::BodyTooLarge));
+    }
+
+    session.enable_retry_buffering();
+
+    let mut body = Vec::new();
+    while let Some(chunk) = session.read_request_body().await? {
+        body.extend_from_slice(&chunk);
+    }
+
+    Ok(Envelope::parse(&body).ok_or(Rejection::InvalidBody))
+}
+
 #[derive(Debug, Default)]
 pub struct Context {
     instance: String,
     consumer: Consumer,
     is_health_request: bool,
+    request_id: String,
+    rejection: Option<Rejection>,
+    is_json_rpc: bool,
+    /// Parsed JSON-RPC request, unset when the body is too large or isn't JSON-RPC.
+    pub envelope: Option<Envelope>,
+    /// Why the body of a request couldn't be parsed as JSON-RPC.
+    body_rejection: Option<Rejection>,
+    /// Units the request counts in the rate limiter and usage, set once it reaches the limiter.
+    pub cost: Option<u64>,
+}
+impl Context {
+    /// Methods called by the request, once per call of a batch.
+    pub fn methods(&self) -> Vec<&str> {
+        self.envelope
+            .iter()
+            .flat_map(|envelope| envelope.calls())
+            .map(|call| call.method.as_str())
+            .collect()
+    }
+
+    /// Requests counted in the metrics, once per call of a batch.
+    fn requests(&self) -> u64 {
+        self.envelope.as_ref().map_or(1, Envelope::requests) as u64
+    }
 }
 
 #[async_trait]
@@ -114,32 +386,126 @@ impl ProxyHttp for TrpProxy {
             return Ok(true);
         }
 
+        ctx.request_id = self.extract_request_id(session);
+        ctx.is_json_rpc = jsonrpc::is_json_rpc(session.req_header());
+
         let Some(key) = self.extract_key(session) else {
-            session.respond_error(401).await?;
+            self.reject_unauthenticated(session, ctx, Rejection::MissingKey)
+                .await?;
             return Ok(true);
         };
 
         let Some(consumer) = self.state.get_consumer(&key).await else {
-            session.respond_error(401).await?;
+            self.reject_unauthenticated(session, ctx, Rejection::InvalidKey)
+                .await?;
             return Ok(true);
         };
 
-        if consumer.network != self.config.network {
-            session.respond_error(401).await?;
+        ctx.consumer = consumer;
+        ctx.instance = self.config.trp_instance.clone();
+
+        // The body is only proxied for known consumers, so it's read once the key is checked.
+        if self.reads_body(session, ctx).await {
+            match read_envelope(session).await? {
+                Ok(envelope) => {
+                    ctx.envelope = Some(envelope);
+                    ctx.is_json_rpc = true;
+                }
+                Err(rejection) => ctx.body_rejection = Some(rejection),
+            }
+        }
+
+        if ctx.consumer.network != self.config.network {
+            self.respond_rejection(session, ctx, Rejection::WrongNetwork, None)
+                .await?;
             return Ok(true);
         }
 
-        ctx.consumer = consumer;
-        ctx.instance = self.config.trp_instance.clone();
+        // The consumer stays known, so the denials of a suspended port are counted under it.
+        if ctx.consumer.suspended {
+            let reason = ctx.consumer.suspend_reason.clone();
+            self.respond_rejection(session, ctx, Rejection::Suspended, reason.as_deref())
+                .await?;
+            return Ok(true);
+        }
+
+        if ctx.consumer.is_over_quota(Utc::now()) {
+            self.respond_rejection(session, ctx, Rejection::QuotaExceeded, None)
+                .await?;
+            return Ok(true);
+        }
 
-        if self.limiter(&ctx.consumer).await? {
-            session.respond_error(429).await?;
+        if let Some(rejection) = self.check_body(ctx).await {
+            self.respond_rejection(session, ctx, rejection, None)
+                .await?;
+            return Ok(true);
+        }
+
+        if let Some(message) = self.check_methods(ctx).await {
+            self.respond_rejection(session, ctx, Rejection::MethodNotAllowed, Some(&message))
+                .await?;
+            return Ok(true);
+        }
+
+        if let Some(message) = self.check_batch_size(ctx).await {
+            self.respond_rejection(session, ctx, Rejection::BatchTooLarge, Some(&message))
+                .await?;
+            return Ok(true);
+        }
+
+        let cost = self.request_cost(ctx).await;
+        ctx.cost = Some(cost);
+
+        if self.limiter(&ctx.consumer, cost).await? {
+            self.respond_rejection(session, ctx, Rejection::RateLimited, None)
+                .await?;
             return Ok(true);
         }
 
         Ok(false)
     }
 
+    /// JSON-RPC clients get a JSON-RPC error when the instance can't be reached, other
+    /// requests keep the default error page.
+    async fn fail_to_proxy(&self, session: &mut Session, e: &Error, ctx: &mut Self::CTX) -> u16
+    where
+        Self::CTX: Send + Sync,
+    {
+        let code = match e.etype() {
+            ErrorType::HTTPStatus(code) => *code,
+            _ => match e.esource() {
+                ErrorSource::Upstream => 502,
+                ErrorSource::Downstream => match e.etype() {
+                    ErrorType::WriteError | ErrorType::ReadError | ErrorType::ConnectionClosed => 0,
+                    _ => 400,
+                },
+                ErrorSource::Internal | ErrorSource::Unset => 500,
+            },
+        };
+        if code == 0 {
+            return code;
+        }
+
+        let result = if ctx.is_json_rpc {
+            let data = json!({ "reason": "upstream_error", "request_id": ctx.request_id });
+            let body = jsonrpc::error_response(
+                ctx.envelope.as_ref(),
+                jsonrpc::UPSTREAM_ERROR,
+                "The instance failed to answer",
+                data,
+            );
+            self.respond_json(session, ctx, code, body).await
+        } else {
+            session.respond_error(code).await
+        };
+
+        if let Err(err) = result {
+            error!(error = err.to_string(), "failed to send error response");
+        }
+
+        code
+    }
+
     async fn upstream_peer(
         &self,
         _session: &mut Session,
@@ -160,17 +526,45 @@ impl ProxyHttp for TrpProxy {
                 .response_written()
                 .map_or(0, |resp| resp.status.as_u16());
 
+            let methods = ctx.methods();
+
             info!(
-                "{} response code: {response_code}",
-                self.request_summary(session, ctx)
+                "{} response code: {response_code} methods: {}",
+                self.request_summary(session, ctx),
+                methods.join(",")
             );
 
+            let reason = ctx.rejection.map(|r| r.code()).unwrap_or("none");
+
             self.state.metrics.inc_http_total_request(
                 &ctx.consumer,
                 &self.config.proxy_namespace,
                 &ctx.instance,
                 &response_code,
+                reason,
+                ctx.requests(),
             );
+
+            // Charged requests always have a units series, even when their methods are free, so
+            // the usage query doesn't fall back to counting their requests.
+            if let Some(cost) = ctx.cost {
+                self.state.metrics.inc_http_total_units(
+                    &ctx.consumer,
+                    &self.config.proxy_namespace,
+                    &ctx.instance,
+                    &response_code,
+                    cost,
+                );
+            }
+
+            let tiers = self.state.tiers.read().await;
+            for method in methods {
+                self.state.metrics.inc_jsonrpc_call(
+                    &ctx.consumer,
+                    jsonrpc::method_label(method, &tiers),
+                    &response_code,
+                );
+            }
         }
     }
 }
@@ -1,4 +1,6 @@
 #!/bin/bash
 cd ../operator
-cargo run --bin crdgen | tfk8s > ../bootstrap/crds/main.tf
-
+# The webhook namespace is left as a terraform reference, tfk8s escapes it.
+WEBHOOK_SERVICE_NAME=operator-webhook WEBHOOK_SERVICE_NAMESPACE='${var.namespace}' cargo run --bin crdgen \
+  | tfk8s \
+  | sed 's/\$\${var.namespace}/${var.namespace}/g' > ../bootstrap/crds/main.tf